@@ -0,0 +1,297 @@
+use builder;
+use digraph;
+use ogg;
+use std::cmp;
+use std::fs;
+use std::path;
+use stream;
+use stream::Stream;
+use vorbis;
+
+/// Jump in amplitude, beyond the roughness of the signal, that counts as a
+/// click.
+pub const DISCONTINUITY_THRESHOLD: f64 = 0.05;
+/// Change in level in decibels that stands out.
+pub const LEVEL_JUMP_THRESHOLD: f64 = 6.0;
+/// Step in DC offset that is heard as a thump.
+pub const DC_OFFSET_THRESHOLD: f64 = 0.01;
+
+/// Level below which a signal counts as silent, about -100 dB.
+const SILENCE: f64 = 1e-5;
+
+/// Length in seconds of the blocks of the energy envelope.
+const BLOCK: f64 = 0.01;
+/// Level in decibels below which a block is not part of the music.
+const ACTIVE: f64 = -60.0;
+/// Decibels below the typical level of the music at which its final sound
+/// has given way to its tail.
+const TAIL_DROP: f64 = 10.0;
+/// Tails shorter than this many seconds are not worth splicing over.
+const MIN_TAIL: f64 = 0.1;
+/// Seconds after a splice point to look for a zero crossing in.
+const ZERO_CROSSING_RANGE: f64 = 0.005;
+
+/// Measurements of the sound where one step of a walk gives way to the next.
+#[derive(Clone, Debug)]
+pub struct Seam {
+    pub first: digraph::Step,
+    pub second: digraph::Step,
+    /// Largest jump of any channel at the seam away from where the samples
+    /// before it were heading, less their usual such jump.
+    pub discontinuity: f64,
+    /// Level after the seam relative to the level before it, in decibels.
+    pub level_jump: f64,
+    /// Largest change of the mean of any channel across the seam.
+    pub dc_offset: f64,
+}
+
+impl Seam {
+    /// Sum of the measurements relative to their thresholds. Seams at or
+    /// above 1 are suspect.
+    pub fn severity(&self) -> f64 {
+        self.discontinuity / DISCONTINUITY_THRESHOLD +
+        self.level_jump.abs() / LEVEL_JUMP_THRESHOLD + self.dc_offset / DC_OFFSET_THRESHOLD
+    }
+
+    pub fn is_suspect(&self) -> bool {
+        self.severity() >= 1.0
+    }
+}
+
+/// Reads `samples` samples from `stream`, padding with silence if it ends.
+fn render(stream: &mut stream::Mixer, samples: usize) -> stream::Result<Vec<f32>> {
+    let mut buf = vec![0.0; samples];
+    let mut position = 0;
+    while position < samples {
+        let len = cmp::min(stream.max_read(), samples - position);
+        if len == 0 {
+            try!(stream.load());
+            if stream.max_read() == 0 {
+                break;
+            }
+            continue;
+        }
+        stream.read_add(&mut buf[position..position + len]);
+        position += len;
+    }
+    Ok(buf)
+}
+
+fn rms(samples: &[f32]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum = samples.iter().fold(0.0, |acc, &x| acc + x as f64 * x as f64);
+    (sum / samples.len() as f64).sqrt()
+}
+
+fn mean(samples: &[f32]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.iter().fold(0.0, |acc, &x| acc + x as f64) / samples.len() as f64
+}
+
+/// Decodes `window` frames on either side of the seam between two steps and
+/// measures it.
+pub fn analyze(first: &digraph::Step,
+               second: &digraph::Step,
+               voice_config: builder::VoiceConfig,
+               window: usize)
+               -> stream::Result<Seam> {
+    let samples = window * cmp::max(voice_config.0 as usize, 1);
+    let tail = try!(builder::seam_window(first, samples, samples));
+    measure(first, &tail, second, voice_config, window)
+}
+
+/// Measures the seam between the window of `first`, decoded `window` frames
+/// on either side of the seam, and `second`.
+fn measure(first: &digraph::Step,
+           tail: &stream::Window,
+           second: &digraph::Step,
+           voice_config: builder::VoiceConfig,
+           window: usize)
+           -> stream::Result<Seam> {
+    let channels = cmp::max(voice_config.0 as usize, 1);
+    let (player, before) = try!(builder::seam(tail, second));
+    let mut mixer = stream::Mixer::new(vec![Box::new(player)]);
+    let samples = try!(render(&mut mixer, before + window * channels));
+    let frames = samples.len() / channels;
+    let seam = cmp::min(before / channels, frames);
+
+    let mut discontinuity: f64 = 0.0;
+    let mut dc_offset: f64 = 0.0;
+    for channel in 0..channels {
+        let channel: Vec<f32> = samples.chunks(channels).map(|frame| frame[channel]).collect();
+        let (head, tail) = channel.split_at(seam);
+        dc_offset = dc_offset.max((mean(tail) - mean(head)).abs());
+        if seam < 3 || seam >= frames {
+            continue;
+        }
+        // How far each sample strays from the line through the two before it.
+        let stray = |i: usize| (channel[i] as f64 - 2.0 * channel[i - 1] as f64 +
+                                channel[i - 2] as f64)
+                                   .abs();
+        let usual = (2..seam).map(&stray).fold(0.0, |acc, x| acc + x) / (seam - 2) as f64;
+        discontinuity = discontinuity.max(stray(seam) - usual);
+    }
+    let (head, tail) = samples.split_at(seam * channels);
+    let level_jump = 20.0 * ((rms(tail) + SILENCE) / (rms(head) + SILENCE)).log10();
+
+    Ok(Seam {
+        first: first.clone(),
+        second: second.clone(),
+        discontinuity: discontinuity,
+        level_jump: level_jump,
+        dc_offset: dc_offset,
+    })
+}
+
+/// Measures every seam of `digraph` as given by `Digraph::seams`, most
+/// severe first, along with the seams that could not be decoded.
+pub fn analyze_all(digraph: &digraph::Digraph,
+                   voice_config: builder::VoiceConfig,
+                   window: usize,
+                   every_variant: bool)
+                   -> (Vec<Seam>, Vec<(digraph::Step, digraph::Step, stream::Error)>) {
+    let samples = window * cmp::max(voice_config.0 as usize, 1);
+    let mut seams = vec![];
+    let mut errors = vec![];
+    // The seams of a step with each that may follow it come in a row, so its
+    // window is decoded once for all of them.
+    let mut tail: Option<(Vec<path::PathBuf>, stream::Window)> = None;
+    for (first, second) in digraph.seams(every_variant) {
+        if tail.as_ref().map_or(true, |&(ref paths, _)| *paths != first.paths) {
+            tail = match builder::seam_window(&first, samples, samples) {
+                Ok(window) => Some((first.paths.clone(), window)),
+                Err(err) => {
+                    errors.push((first, second, err));
+                    continue;
+                }
+            };
+        }
+        if let Some((_, ref tail)) = tail {
+            match measure(&first, tail, &second, voice_config, window) {
+                Ok(seam) => seams.push(seam),
+                Err(err) => errors.push((first, second, err)),
+            }
+        }
+    }
+    seams.sort_by(|a, b| b.severity().partial_cmp(&a.severity()).unwrap_or(cmp::Ordering::Equal));
+    (seams, errors)
+}
+
+/// A splice point proposed for a track file.
+#[derive(Clone, Debug)]
+pub struct Suggestion {
+    pub channels: u8,
+    pub rate: u32,
+    pub frames: u64,
+    /// Frame at which the music gives way to its tail.
+    pub musical_end: u64,
+    /// Proposed value of the SPLICEPOINT comment, in samples, if the file
+    /// has a tail worth splicing over.
+    pub splice_point: Option<u64>,
+    /// The SPLICEPOINT comments of the file.
+    pub current: Vec<String>,
+}
+
+impl Suggestion {
+    pub fn seconds(&self, samples: u64) -> f64 {
+        samples as f64 / (self.channels as f64 * self.rate as f64)
+    }
+}
+
+/// Decodes a file to the mean of its channels.
+fn downmix(path: &path::Path, channels: usize) -> stream::Result<Vec<f32>> {
+    let decoder = try!(vorbis::Decoder::new(try!(fs::File::open(path))));
+    let mut stream = try!(stream::VorbisStream::new(decoder));
+    let mut mix = vec![];
+    let mut buf = vec![];
+    loop {
+        let len = stream.max_read();
+        if len == 0 {
+            if stream.is_eos() {
+                return Ok(mix);
+            }
+            try!(stream.load());
+            continue;
+        }
+        buf.truncate(0);
+        buf.resize(len, 0.0);
+        stream.read_add(&mut buf);
+        mix.extend(buf.chunks(channels)
+                      .map(|frame| frame.iter().fold(0.0, |acc, x| acc + x) / channels as f32));
+    }
+}
+
+/// The frame of `mix` at which the music ends and its tail begins.
+fn musical_end(mix: &[f32], rate: u32, tempo: Option<f64>) -> usize {
+    let block = cmp::max(1, (BLOCK * rate as f64).round() as usize);
+
+    let levels: Vec<f64> = mix.chunks(block)
+                              .map(|samples| 20.0 * (rms(samples) + SILENCE).log10())
+                              .collect();
+    let mut active: Vec<f64> = levels.iter().cloned().filter(|&level| level > ACTIVE).collect();
+    active.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+    let median = active.get(active.len() / 2).cloned().unwrap_or(ACTIVE);
+    let last_loud = levels.iter().rposition(|&level| level >= median - TAIL_DROP);
+    let mut end = last_loud.map_or(0, |i| (i + 1) * block);
+
+    if let Some(bpm) = tempo {
+        let beat = rate as f64 * 60.0 / bpm;
+        if beat >= 1.0 {
+            // Rounding down would cut into the last beat of the music.
+            end = ((end as f64 / beat - 1e-9).ceil() * beat).round() as usize;
+        }
+    }
+    end = cmp::min(end, mix.len());
+
+    let range = (ZERO_CROSSING_RANGE * rate as f64).round() as usize;
+    let crossing = (end..end + range + 1)
+                       .filter(|&i| i >= 1 && i < mix.len())
+                       .find(|&i| (mix[i - 1] <= 0.0) != (mix[i] <= 0.0));
+    crossing.unwrap_or(end)
+}
+
+/// Proposes a splice point for a track file where its music ends and its
+/// tail, e.g. of reverb, begins.
+///
+/// The end of the music is where the energy envelope last falls
+/// `TAIL_DROP` decibels below the median level of the music. Given the
+/// `tempo` in beats per minute, it is moved on to the next beat counted from
+/// the start of the file. Finally it is moved on to the next zero crossing
+/// within `ZERO_CROSSING_RANGE`, so as not to cut into the music.
+pub fn suggest_splice_point(path: &path::Path, tempo: Option<f64>) -> stream::Result<Suggestion> {
+    let info = try!(ogg::info(path));
+    let channels = cmp::max(info.channels as usize, 1);
+    let mix = try!(downmix(path, channels));
+    let end = musical_end(&mix, info.rate, tempo);
+    let tail = (mix.len() - cmp::min(end, mix.len())) as f64 / info.rate as f64;
+    let splice_point = if tail >= MIN_TAIL && end > 0 {
+        Some(end as u64 * channels as u64)
+    } else {
+        None
+    };
+    Ok(Suggestion {
+        channels: info.channels,
+        rate: info.rate,
+        frames: info.frames,
+        musical_end: end as u64,
+        splice_point: splice_point,
+        current: info.comments.get("SPLICEPOINT").iter().map(|value| value.to_string()).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::musical_end;
+
+    #[test]
+    fn musical_end_moves_on_to_the_next_beat() {
+        let mut mix: Vec<f32> = (0..22).map(|i| if i % 2 == 0 { 0.5 } else { -0.5 }).collect();
+        mix.resize(100, 0.0);
+        assert_eq!(musical_end(&mix, 100, None), 22);
+        assert_eq!(musical_end(&mix, 100, Some(600.0)), 30);
+    }
+}
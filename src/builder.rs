@@ -0,0 +1,811 @@
+use compiled;
+use digraph;
+use grammar;
+use history;
+use manifest;
+use ogg;
+use rand;
+use std::cell;
+use std::cmp;
+use std::collections;
+use std::ffi;
+use std::fmt;
+use std::fs;
+use std::path;
+use std::rc;
+use std::str::FromStr;
+use stream;
+use timeline;
+use vorbis;
+
+/// Number of channels and sample rate.
+pub type VoiceConfig = (u8, u32);
+
+/// Name of the subdirectory of a digraph directory holding its stingers.
+///
+/// Stingers are track files played over the walk on demand. Each is named
+/// after its file name without the extension.
+pub const STINGER_DIR: &'static str = "stingers";
+
+pub struct PlayerBuilder<'a> {
+    grammar: &'a grammar::Grammar,
+    digraph_builder: digraph::DigraphBuilder,
+    voice_config: Option<VoiceConfig>,
+    compiled: Option<(VoiceConfig, digraph::Digraph)>,
+    warnings: Vec<stream::Error>,
+}
+
+impl<'a> PlayerBuilder<'a> {
+    pub fn new(grammar: &'a grammar::Grammar) -> PlayerBuilder<'a> {
+        PlayerBuilder {
+            grammar: grammar,
+            digraph_builder: digraph::DigraphBuilder::new(),
+            voice_config: None,
+            compiled: None,
+            warnings: vec![],
+        }
+    }
+
+    /// Reads the number of channels and sample rate of a track file from its
+    /// first audio packet.
+    pub fn path_to_voice_config(path: &path::Path) -> stream::Result<VoiceConfig> {
+        let file = try!(fs::File::open(path));
+        let mut decoder = try!(vorbis::Decoder::new(file));
+        let packet = try!(try!(decoder.packets().next().ok_or_else(|| {
+            stream::Error::Ogg("no audio packets".to_string())
+        })));
+        Ok((packet.channels as u8, packet.rate as u32))
+    }
+
+    pub fn path(&mut self, path: path::PathBuf) -> stream::Result<&mut Self> {
+        let grammar = self.grammar;
+        self.path_with_grammar(grammar, path)
+    }
+
+    fn path_with_grammar(&mut self,
+                         grammar: &grammar::Grammar,
+                         path: path::PathBuf)
+                         -> stream::Result<&mut Self> {
+        if let Some((tail, head, variant)) = grammar.section(&path) {
+            match Self::path_to_voice_config(&path) {
+                Ok(file_voice_config) => {
+                    self.voice_config = self.voice_config.or(Some(file_voice_config));
+                    if Some(file_voice_config) != self.voice_config {
+                        return Err(stream::Error::AudioFormat);
+                    }
+                    // Read the length now, as tracks that crossfade without a
+                    // splice point need it and the playback loop cannot wait.
+                    let frames = ogg::info(&path).ok().map(|info| info.frames);
+                    let dwell = if tail == head {
+                        self.dwell(&tail, &path)
+                    } else {
+                        Ok(())
+                    };
+                    let variant = digraph::Variant::new(path, variant.as_ref().map(String::as_str))
+                                      .with_frames(frames);
+                    self.digraph_builder.arrow(tail, head, variant);
+                    // The track is played even if its dwell comments are
+                    // rejected.
+                    try!(dwell);
+                    Ok(self)
+                }
+                Err(err) => Err(stream::Error::File(path, Box::new(err))),
+            }
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// Applies the dwell counts set by the comments of a self-loop file of
+    /// `node`. The smallest count of each kind among its files applies.
+    fn dwell(&mut self, node: &str, path: &path::Path) -> stream::Result<()> {
+        let (min, max) = try!(stream::dwell(path)
+                                  .map_err(|err| stream::Error::File(path.to_path_buf(),
+                                                                     Box::new(err))));
+        let (node_min, node_max) = self.digraph_builder.dwell(node);
+        if let Some(min) = min {
+            let min = node_min.map_or(min as usize, |node_min| cmp::min(node_min, min as usize));
+            self.digraph_builder.min_dwell(node, min);
+        }
+        if let Some(max) = max {
+            let max = node_max.map_or(max as usize, |node_max| cmp::min(node_max, max as usize));
+            self.digraph_builder.max_dwell(node, max);
+        }
+        match self.digraph_builder.dwell(node) {
+            (Some(min), Some(max)) if min > max => {
+                let message = "MINDWELL exceeds MAXDWELL".to_string();
+                Err(stream::Error::File(path.to_path_buf(), Box::new(stream::Error::Ogg(message))))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Uses the compiled digraph of a directory, or scans it if there is none.
+    ///
+    /// A compiled digraph older than any file in the directory is recorded
+    /// as a warning and the directory is scanned instead.
+    pub fn dir(&mut self, dir: &str) -> stream::Result<&mut Self> {
+        let dir_path = path::Path::new(dir);
+        let compiled_path = dir_path.join(compiled::FILE_NAME);
+        if compiled_path.is_file() && try!(compiled::is_outdated(dir_path)) {
+            self.warnings.push(stream::Error::Outdated(compiled_path));
+        } else if let Some(compiled) = try!(compiled::load(dir_path)) {
+            self.compiled = Some(compiled);
+            return Ok(self);
+        }
+        self.scan(dir)
+    }
+
+    /// Adds every track file and the manifest of a directory.
+    ///
+    /// A pattern set by the manifest replaces the file name pattern of the
+    /// builder for the directory. Files that cannot be added are skipped and
+    /// recorded as warnings.
+    pub fn scan(&mut self, dir: &str) -> stream::Result<&mut Self> {
+        let manifest_path = path::Path::new(dir).join(manifest::FILE_NAME);
+        let manifest_grammar = if manifest_path.is_file() {
+            try!(manifest::grammar(&manifest_path).map_err(|err| {
+                stream::Error::File(manifest_path.clone(), Box::new(err))
+            }))
+        } else {
+            None
+        };
+        let grammar = manifest_grammar.as_ref().unwrap_or(self.grammar);
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            if let Err(err) = self.path_with_grammar(grammar, entry.path()) {
+                self.warnings.push(err);
+            }
+        }
+        if manifest_path.is_file() {
+            try!(manifest::load_with_grammar(&manifest_path, grammar, &mut self.digraph_builder)
+                     .map_err(|err| stream::Error::File(manifest_path, Box::new(err))));
+        }
+        Ok(self)
+    }
+
+    pub fn warnings(&self) -> &[stream::Error] {
+        &self.warnings
+    }
+
+    pub fn into_digraph(self) -> Option<(VoiceConfig, digraph::Digraph)> {
+        let digraph_builder = self.digraph_builder;
+        let voice_config = self.voice_config;
+        self.compiled.or_else(|| {
+            voice_config.map(|voice_config| (voice_config, digraph_builder.into()))
+        })
+    }
+
+    pub fn build(self,
+                 steering: digraph::Steering)
+                 -> stream::Result<Option<(VoiceConfig, stream::Player)>> {
+        if let Some((voice_config, digraph)) = self.into_digraph() {
+            let walk = digraph.into_random_walk(Box::new(rand::thread_rng()), steering);
+            let options = PlayerOptions::new(voice_config);
+            Ok(Some((voice_config, try!(player(walk, &options)))))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Opens the files of a step, stacking them if there are several stems.
+pub fn step_to_track(step: digraph::Step) -> stream::Result<stream::Track> {
+    paths_to_track(&step.paths, step.crossfade, step.frames)
+}
+
+fn paths_to_track(paths: &[path::PathBuf],
+                  crossfade: Option<f64>,
+                  frames: Option<u64>)
+                  -> stream::Result<stream::Track> {
+    let open = |path: &path::PathBuf| {
+        stream::Track::vorbis_with_crossfade(path, crossfade, frames)
+            .map_err(|err| stream::Error::File(path.clone(), Box::new(err)))
+    };
+    if paths.len() == 1 {
+        open(&paths[0])
+    } else {
+        let mut tracks = Vec::with_capacity(paths.len());
+        for path in paths.iter() {
+            tracks.push(try!(open(path)));
+        }
+        Ok(stream::Track::stack(tracks))
+    }
+}
+
+/// Decodes the files of a step around where the next step starts, keeping
+/// up to `before` samples ahead of it and `after` samples of the tail past
+/// it, for `seam` to play it with each step that may follow.
+pub fn seam_window(first: &digraph::Step,
+                   before: usize,
+                   after: usize)
+                   -> stream::Result<stream::Window> {
+    let track = try!(paths_to_track(&first.paths, first.crossfade, first.frames));
+    track.window(before, after)
+}
+
+/// Opens a player of the window of a step followed by the files of `second`.
+///
+/// Returns the player along with the number of samples it plays before the
+/// second step starts.
+pub fn seam(window: &stream::Window,
+            second: &digraph::Step)
+            -> stream::Result<(stream::Player, usize)> {
+    let next = try!(paths_to_track(&second.paths, second.crossfade, second.frames));
+    let tracks = vec![Ok(window.track()), Ok(next)];
+    let player = try!(stream::Player::new(Box::new(tracks.into_iter())));
+    Ok((player, window.before()))
+}
+
+/// How `player` sets up a player, beyond the settings of its walk.
+///
+/// Options are set through methods, so that new ones can be added without
+/// breaking callers.
+#[derive(Clone)]
+pub struct PlayerOptions {
+    voice_config: VoiceConfig,
+    cue: Option<stream::Cue>,
+}
+
+impl PlayerOptions {
+    /// The options for playing files of `voice_config`.
+    pub fn new(voice_config: VoiceConfig) -> PlayerOptions {
+        PlayerOptions {
+            voice_config: voice_config,
+            cue: None,
+        }
+    }
+
+    /// Makes the player count the tracks it starts on `cue`, e.g. one shared
+    /// with the other players of a layer, instead of a cue of its own.
+    pub fn cue(&mut self, cue: stream::Cue) -> &mut Self {
+        self.cue = Some(cue);
+        self
+    }
+}
+
+/// Creates a player of the tracks of a walk.
+///
+/// The walk tells the time by the samples read from the player. What it
+/// records and how it chooses are set up through `IntoRandomWalk`.
+pub fn player(walk: digraph::IntoRandomWalk,
+              options: &PlayerOptions)
+              -> stream::Result<stream::Player> {
+    let voice_config = options.voice_config;
+    let steering = walk.steering().clone();
+    let mut player = try!(stream::Player::new(Box::new(walk.map(step_to_track))));
+    if let Some(ref cue) = options.cue {
+        player.set_cue(cue.clone());
+    }
+    steering.set_clock(player.clock(),
+                       voice_config.0 as f64 * voice_config.1 as f64);
+    Ok(player)
+}
+
+/// Loads the digraphs of the stems of a layer and stacks them.
+///
+/// Files that cannot be added are skipped and recorded in `warnings`.
+pub fn stems(grammar: &grammar::Grammar,
+         dirs: &[String],
+         warnings: &mut Vec<stream::Error>)
+         -> stream::Result<(VoiceConfig, digraph::Digraph)> {
+    let mut stacked: Option<(VoiceConfig, digraph::Digraph)> = None;
+    for dir in dirs {
+        let res = {
+            let mut player_builder = PlayerBuilder::new(grammar);
+            let res = player_builder.dir(dir).map(|_| ());
+            warnings.extend(player_builder.warnings.drain(..));
+            res.and_then(|_| player_builder.into_digraph().ok_or(stream::Error::NoItems))
+        };
+        let (voice_config, digraph) = try!(res.map_err(|err| {
+            stream::Error::Dir(dir.clone(), Box::new(err))
+        }));
+        stacked = Some(match stacked {
+            Some((stacked_voice_config, stacked_digraph)) => {
+                if voice_config != stacked_voice_config {
+                    return Err(stream::Error::AudioFormat);
+                }
+                let digraph = try!(stacked_digraph.stack(digraph).map_err(|err| {
+                    stream::Error::Dir(dir.clone(), Box::new(err))
+                }));
+                (voice_config, digraph)
+            }
+            None => (voice_config, digraph),
+        });
+    }
+    stacked.ok_or(stream::Error::NoItems)
+}
+
+/// Finds the stingers of the stems of a layer.
+///
+/// Stingers of the same name in several stems are played together.
+fn stingers(dirs: &[String],
+            voice_config: VoiceConfig,
+            warnings: &mut Vec<stream::Error>)
+            -> collections::BTreeMap<String, Vec<path::PathBuf>> {
+    let mut stingers = collections::BTreeMap::new();
+    for dir in dirs {
+        let entries = match fs::read_dir(path::Path::new(dir).join(STINGER_DIR)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    warnings.push(stream::Error::Io(err));
+                    continue;
+                }
+            };
+            if path.extension() != Some(ffi::OsStr::new("ogg")) {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) => stem.to_lowercase(),
+                None => continue,
+            };
+            match PlayerBuilder::path_to_voice_config(&path) {
+                Ok(file_voice_config) if file_voice_config == voice_config => {
+                    stingers.entry(name).or_insert_with(|| vec![]).push(path);
+                }
+                Ok(_) => {
+                    warnings.push(stream::Error::File(path, Box::new(stream::Error::AudioFormat)))
+                }
+                Err(err) => warnings.push(stream::Error::File(path, Box::new(err))),
+            }
+        }
+    }
+    stingers
+}
+
+fn node_names(digraph: &digraph::Digraph) -> Vec<String> {
+    digraph.nodes().iter().map(|node| node.name().to_string()).collect()
+}
+
+/// When a stinger starts playing.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Quantize {
+    /// Right away.
+    Now,
+    /// Along with the next track of the layer.
+    Splice,
+    /// On the next beat of the layer, counted from the start of the mixer.
+    Beat,
+}
+
+impl fmt::Display for Quantize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Quantize::Now => write!(f, "now"),
+            Quantize::Splice => write!(f, "splice"),
+            Quantize::Beat => write!(f, "beat"),
+        }
+    }
+}
+
+impl FromStr for Quantize {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Quantize, ()> {
+        match s {
+            "now" => Ok(Quantize::Now),
+            "splice" => Ok(Quantize::Splice),
+            "beat" => Ok(Quantize::Beat),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The players of the walkers of a digraph in a mixer, along with the
+/// directories it was built from.
+pub struct Layer {
+    dirs: Vec<String>,
+    steerings: Vec<digraph::Steering>,
+    controls: Vec<stream::Control>,
+    skipped: Vec<rc::Rc<cell::RefCell<Vec<stream::Error>>>>,
+    voice_config: VoiceConfig,
+    nodes: cell::RefCell<Vec<String>>,
+    tempo: cell::Cell<Option<f64>>,
+    stingers: collections::BTreeMap<String, Vec<path::PathBuf>>,
+    cue: stream::Cue,
+}
+
+impl Layer {
+    /// The directories of the stems of the layer.
+    pub fn dirs(&self) -> &[String] {
+        &self.dirs
+    }
+
+    /// The name of the layer, i.e. its directories separated by commas.
+    pub fn name(&self) -> String {
+        self.dirs.join(",")
+    }
+
+    /// The steering of the first walker of the layer.
+    pub fn steering(&self) -> &digraph::Steering {
+        &self.steerings[0]
+    }
+
+    /// The steerings of the walkers of the layer.
+    pub fn steerings(&self) -> &[digraph::Steering] {
+        &self.steerings
+    }
+
+    /// The controls of the players of the walkers of the layer.
+    pub fn controls(&self) -> &[stream::Control] {
+        &self.controls
+    }
+
+    /// Takes the errors of the tracks the players of the layer skipped
+    /// because they failed to open.
+    pub fn take_skipped(&self) -> Vec<stream::Error> {
+        let mut errors = vec![];
+        for skipped in self.skipped.iter() {
+            errors.extend(skipped.borrow_mut().drain(..));
+        }
+        errors
+    }
+
+    pub fn has_node(&self, node: &str) -> bool {
+        self.node(node).is_some()
+    }
+
+    /// Spells `node` the way the digraph of the layer does, matching case
+    /// insensitively if there is no exact match.
+    pub fn node(&self, node: &str) -> Option<String> {
+        let nodes = self.nodes.borrow();
+        let lowercase = node.to_lowercase();
+        nodes.iter()
+             .find(|name| *name == node)
+             .or_else(|| nodes.iter().find(|name| name.to_lowercase() == lowercase))
+             .cloned()
+    }
+
+    /// Sends the walks of the layer back to "start", cutting their tracks
+    /// short and fading them out over `fade` seconds.
+    pub fn reset(&self, fade: f64) {
+        for steering in self.steerings.iter() {
+            steering.reset();
+        }
+        self.skip(fade);
+    }
+
+    /// Moves the walks of the layer over to `node`, cutting their tracks
+    /// short and fading them out over `fade` seconds.
+    pub fn jump(&self, node: &str, fade: f64) -> stream::Result<()> {
+        let node = try!(self.node(node).ok_or_else(|| stream::Error::NoNode(node.to_string())));
+        for steering in self.steerings.iter() {
+            steering.jump(&node);
+        }
+        self.skip(fade);
+        Ok(())
+    }
+
+    /// Has each walker of the layer go on with the walk `walk` makes for its
+    /// steering, cutting their tracks short and fading them out over `fade`
+    /// seconds, if any.
+    ///
+    /// The new walk keeps the steering, so it tells the time by the same
+    /// player and sets the level of the same layer.
+    pub fn swap<F>(&self, mut walk: F, fade: Option<f64>)
+        where F: FnMut(&digraph::Steering) -> digraph::IntoRandomWalk
+    {
+        let frames = fade.map(|fade| self.frames(fade));
+        for (control, steering) in self.controls.iter().zip(self.steerings.iter()) {
+            control.swap(Box::new(walk(steering).map(step_to_track)), frames);
+        }
+    }
+
+    fn skip(&self, fade: f64) {
+        let frames = self.frames(fade);
+        for control in self.controls.iter() {
+            control.skip(frames);
+        }
+    }
+
+    fn frames(&self, seconds: f64) -> usize {
+        (seconds.max(0.0) * self.voice_config.1 as f64).round() as usize
+    }
+
+    /// The names of the stingers of the layer.
+    pub fn stingers(&self) -> Vec<&str> {
+        self.stingers.keys().map(String::as_str).collect()
+    }
+
+    /// Mixes the stinger `name` into `mixer`, starting as given by
+    /// `quantize`. With several walkers, `Quantize::Splice` goes by whichever
+    /// starts its next track first.
+    ///
+    /// The stinger is mixed outside the fader of the layer, so it is heard at
+    /// full level whatever the intensity.
+    pub fn sting(&self,
+                 mixer: &mut stream::Mixer,
+                 name: &str,
+                 quantize: Quantize)
+                 -> stream::Result<()> {
+        let paths = try!(self.stingers
+                             .get(name)
+                             .ok_or_else(|| stream::Error::NoStinger(name.to_string())));
+        let track = try!(paths_to_track(paths, None, None));
+        match quantize {
+            Quantize::Now => mixer.push(Box::new(track)),
+            Quantize::Splice => mixer.push(Box::new(self.cue.wait(Box::new(track)))),
+            Quantize::Beat => {
+                let bpm = try!(self.tempo
+                                   .get()
+                                   .ok_or_else(|| stream::Error::NoTempo(self.name())));
+                let channels = self.voice_config.0 as u64;
+                let beat = cmp::max(1, (self.voice_config.1 as f64 * 60.0 / bpm).round() as u64);
+                let frame = mixer.position() / channels;
+                let delay = (beat - frame % beat) % beat;
+                mixer.push(Box::new(stream::Delayed::new(Box::new(track),
+                                                         (delay * channels) as usize)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rescans the directories of the layer and hands the result to its walks.
+    ///
+    /// Returns the warnings from scanning the directories.
+    pub fn reload(&self, grammar: &grammar::Grammar) -> stream::Result<Vec<stream::Error>> {
+        let mut warnings = vec![];
+        let (voice_config, digraph) = try!(stems(grammar, &self.dirs, &mut warnings));
+        try!(self.replace(voice_config, digraph));
+        Ok(warnings)
+    }
+
+    /// Hands a digraph loaded from the directories of the layer, e.g. by
+    /// `stems` on another thread, to its walks.
+    pub fn replace(&self,
+                   voice_config: VoiceConfig,
+                   digraph: digraph::Digraph)
+                   -> stream::Result<()> {
+        if voice_config != self.voice_config {
+            return Err(stream::Error::AudioFormat);
+        }
+        self.tempo.set(digraph.tempo());
+        *self.nodes.borrow_mut() = node_names(&digraph);
+        let digraph = rc::Rc::new(digraph);
+        for steering in self.steerings.iter() {
+            steering.replace_digraph(digraph.clone());
+        }
+        Ok(())
+    }
+}
+
+pub struct MixerBuilder<'a> {
+    grammar: &'a grammar::Grammar,
+    layers: Vec<Layer>,
+    streams: Vec<Box<stream::Stream>>,
+    voice_config: Option<VoiceConfig>,
+    intensity: Option<f64>,
+    history: Option<history::History>,
+    timeline: Option<timeline::Timeline>,
+    crossfade: Option<f64>,
+    warnings: Vec<stream::Error>,
+}
+
+impl<'a> MixerBuilder<'a> {
+    pub fn new(grammar: &'a grammar::Grammar) -> MixerBuilder<'a> {
+        MixerBuilder {
+            grammar: grammar,
+            layers: vec![],
+            streams: vec![],
+            voice_config: None,
+            intensity: None,
+            history: None,
+            timeline: None,
+            crossfade: None,
+            warnings: vec![],
+        }
+    }
+
+    /// Adds a layer of a single directory, walked once.
+    ///
+    /// This is `canon` with one directory, one walker and no offset.
+    pub fn dir(&mut self, dir: &str) -> stream::Result<&mut Self> {
+        self.canon(&[dir], 1, 0.0)
+    }
+
+    /// Sets the intensity that the layers added from here on start out at.
+    pub fn intensity(&mut self, intensity: Option<f64>) -> &mut Self {
+        self.intensity = intensity;
+        self
+    }
+
+    /// Makes the layers added from here on favour material played fewer
+    /// times according to `history`, and record what they play in it.
+    pub fn history(&mut self, history: history::History) -> &mut Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Makes the layers added from here on record the tracks they play in
+    /// `timeline`.
+    pub fn timeline(&mut self, timeline: timeline::Timeline) -> &mut Self {
+        self.timeline = Some(timeline);
+        self
+    }
+
+    /// Makes the tracks of the layers added from here on crossfade into the
+    /// next over `seconds`, unless their arrows or files say otherwise.
+    pub fn crossfade(&mut self, seconds: Option<f64>) -> &mut Self {
+        self.crossfade = seconds;
+        self
+    }
+
+    /// Adds a layer whose stems share a single walk.
+    ///
+    /// The stem directories must have identical digraphs. The files of
+    /// corresponding variants are started together and mixed at full level.
+    ///
+    /// The layer fades in and out over a second as the intensity enters and
+    /// leaves the range of its digraph.
+    pub fn stems(&mut self, dirs: &[&str]) -> stream::Result<&mut Self> {
+        self.canon(dirs, 1, 0.0)
+    }
+
+    /// Adds a layer like `stems` with `walkers` independent walks over its
+    /// digraph, each starting `offset` seconds after the one before.
+    ///
+    /// The directories are loaded once and the walks share the result.
+    pub fn canon(&mut self,
+                 dirs: &[&str],
+                 walkers: usize,
+                 offset: f64)
+                 -> stream::Result<&mut Self> {
+        let dirs: Vec<String> = dirs.iter().map(|dir| dir.to_string()).collect();
+        let (voice_config, digraph) = try!(stems(self.grammar, &dirs, &mut self.warnings));
+        self.voice_config = self.voice_config.or(Some(voice_config));
+        if Some(voice_config) != self.voice_config {
+            return Err(stream::Error::Dir(dirs.join(","), Box::new(stream::Error::AudioFormat)));
+        }
+        let stingers = stingers(&dirs, voice_config, &mut self.warnings);
+        let tempo = digraph.tempo();
+        let nodes = node_names(&digraph);
+        let name = dirs.join(",");
+        let frames = (offset.max(0.0) * voice_config.1 as f64).round() as usize;
+        let mut steerings = vec![];
+        let mut controls = vec![];
+        let mut skipped = vec![];
+        let cue = stream::Cue::default();
+        let mut options = PlayerOptions::new(voice_config);
+        options.cue(cue.clone());
+        let digraph = rc::Rc::new(digraph);
+        for i in 0..cmp::max(1, walkers) {
+            let steering = digraph::Steering::new();
+            steering.set_intensity(self.intensity);
+            let mut walk = digraph::IntoRandomWalk::new(digraph.clone(),
+                                                        Box::new(rand::thread_rng()),
+                                                        steering.clone());
+            if let Some(ref history) = self.history {
+                walk.set_history(history.clone());
+            }
+            walk.set_crossfade(self.crossfade);
+            if let Some(ref timeline) = self.timeline {
+                let walker = if walkers > 1 {
+                    format!("{}#{}", name, i + 1)
+                } else {
+                    name.clone()
+                };
+                walk.set_timeline(timeline.clone(),
+                                  &walker,
+                                  i as f64 * offset.max(0.0),
+                                  voice_config.1);
+            }
+            let player = try!(player(walk, &options));
+            controls.push(player.control());
+            skipped.push(player.skipped());
+            let delay = i * frames * voice_config.0 as usize;
+            let delayed = stream::Delayed::new(Box::new(player), delay);
+            self.streams.push(Box::new(stream::Fader::new(Box::new(delayed),
+                                                          steering.gain(),
+                                                          voice_config.0 as usize,
+                                                          voice_config.1 as usize)));
+            steerings.push(steering);
+        }
+        self.layers.push(Layer {
+            dirs: dirs,
+            steerings: steerings,
+            controls: controls,
+            skipped: skipped,
+            voice_config: voice_config,
+            nodes: cell::RefCell::new(nodes),
+            tempo: cell::Cell::new(tempo),
+            stingers: stingers,
+            cue: cue,
+        });
+        Ok(self)
+    }
+
+    /// Makes the walks of the layer named `target` head for `target_node`
+    /// whenever a walk of the layer named `source` enters `node`.
+    ///
+    /// Node names are matched the way `Layer::node` does, and a node that is
+    /// in neither digraph is an error.
+    pub fn couple(&mut self,
+                  source: &str,
+                  node: &str,
+                  target: &str,
+                  target_node: &str)
+                  -> stream::Result<&mut Self> {
+        {
+            let find = |name: &str| {
+                self.layers
+                    .iter()
+                    .find(|layer| layer.name() == name)
+                    .ok_or_else(|| stream::Error::NoLayer(name.to_string()))
+            };
+            let source = try!(find(source));
+            let target = try!(find(target));
+            let node = try!(source.node(node)
+                                  .ok_or_else(|| stream::Error::NoNode(node.to_string())));
+            let target_node = try!(target.node(target_node).ok_or_else(|| {
+                stream::Error::NoNode(target_node.to_string())
+            }));
+            for source in source.steerings.iter() {
+                for target in target.steerings.iter() {
+                    source.couple(&node, target, &target_node);
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// Takes the warnings recorded while adding directories so far.
+    pub fn take_warnings(&mut self) -> Vec<stream::Error> {
+        self.warnings.drain(..).collect()
+    }
+
+    pub fn build(self) -> stream::Result<(VoiceConfig, f32, stream::Mixer, Vec<Layer>)> {
+        if let Some(voice_config) = self.voice_config {
+            let coefficient = 1.0 / self.streams.len() as f32;
+            Ok((voice_config, coefficient, stream::Mixer::new(self.streams), self.layers))
+        } else {
+            Err(stream::Error::NoItems)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(dir: &str, nodes: &[&str]) -> Layer {
+        Layer {
+            dirs: vec![dir.to_string()],
+            steerings: vec![digraph::Steering::new()],
+            controls: vec![],
+            skipped: vec![],
+            voice_config: (2, 44100),
+            nodes: cell::RefCell::new(nodes.iter().map(|node| node.to_string()).collect()),
+            tempo: cell::Cell::new(None),
+            stingers: collections::BTreeMap::new(),
+            cue: stream::Cue::default(),
+        }
+    }
+
+    fn no_node<T>(res: stream::Result<T>) -> Option<String> {
+        match res {
+            Err(stream::Error::NoNode(node)) => Some(node),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn couple_requires_both_nodes() {
+        let grammar = grammar::Grammar::default();
+        let mut builder = MixerBuilder::new(&grammar);
+        builder.layers.push(layer("melody", &["start", "boss"]));
+        builder.layers.push(layer("percussion", &["start", "calm", "intense"]));
+        assert_eq!(no_node(builder.couple("melody", "bos", "percussion", "intense")),
+                   Some("bos".to_string()));
+        assert_eq!(no_node(builder.couple("melody", "boss", "percussion", "intens")),
+                   Some("intens".to_string()));
+        match builder.couple("melody", "boss", "drums", "intense") {
+            Err(stream::Error::NoLayer(ref layer)) if layer == "drums" => {}
+            _ => panic!("expected no layer 'drums'"),
+        }
+        assert!(builder.couple("melody", "Boss", "percussion", "intense").is_ok());
+    }
+}
@@ -0,0 +1,107 @@
+use digraph123::Quantize;
+use digraph123::digraph;
+use digraph123::stream;
+use std::io;
+use std::io::BufRead;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+pub enum Command {
+    Tags(Option<digraph::Tags>),
+    Goto(Option<String>),
+    Intensity(Option<f64>),
+    Sting(String, Quantize),
+    Reset(f64),
+    Jump(String, f64),
+}
+
+/// Parses an optional fade out time in seconds, defaulting to none.
+fn parse_fade(word: Option<&str>) -> Option<f64> {
+    match word.map(f64::from_str) {
+        Some(Ok(fade)) if fade >= 0.0 => Some(fade),
+        Some(_) => None,
+        None => Some(0.0),
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> stream::Result<Command> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("tags") => {
+                let tags: digraph::Tags = words.flat_map(|word| digraph::parse_tags(word))
+                                               .collect();
+                if tags.is_empty() {
+                    Ok(Command::Tags(None))
+                } else {
+                    Ok(Command::Tags(Some(tags)))
+                }
+            }
+            Some("goto") => Ok(Command::Goto(words.next().map(str::to_string))),
+            Some("intensity") => {
+                match words.next().map(f64::from_str) {
+                    Some(Ok(intensity)) if intensity >= 0.0 && intensity <= 1.0 => {
+                        Ok(Command::Intensity(Some(intensity)))
+                    }
+                    None => Ok(Command::Intensity(None)),
+                    _ => Err(stream::Error::Command(line.to_string())),
+                }
+            }
+            Some("sting") => {
+                match (words.next(), words.next().map(Quantize::from_str)) {
+                    (Some(name), None) => Ok(Command::Sting(name.to_lowercase(), Quantize::Now)),
+                    (Some(name), Some(Ok(quantize))) => {
+                        Ok(Command::Sting(name.to_lowercase(), quantize))
+                    }
+                    _ => Err(stream::Error::Command(line.to_string())),
+                }
+            }
+            Some("reset") => {
+                match (parse_fade(words.next()), words.next()) {
+                    (Some(fade), None) => Ok(Command::Reset(fade)),
+                    _ => Err(stream::Error::Command(line.to_string())),
+                }
+            }
+            Some("jump") => {
+                match (words.next(), parse_fade(words.next()), words.next()) {
+                    (Some(node), Some(fade), None) => Ok(Command::Jump(node.to_string(), fade)),
+                    _ => Err(stream::Error::Command(line.to_string())),
+                }
+            }
+            _ => Err(stream::Error::Command(line.to_string())),
+        }
+    }
+
+    pub fn apply(&self, steering: &digraph::Steering) {
+        match self {
+            &Command::Tags(ref tags) => steering.set_tags(tags.clone()),
+            &Command::Goto(ref node) => steering.request(node.clone()),
+            &Command::Intensity(intensity) => steering.set_intensity(intensity),
+            // These act on the players and the mixer rather than steer.
+            &Command::Sting(_, _) |
+            &Command::Reset(_) |
+            &Command::Jump(_, _) => {}
+        }
+    }
+}
+
+/// Reads commands from standard input, one per line, on a separate thread.
+pub fn spawn_reader() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(ref line) if line.trim().is_empty() => {}
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
@@ -0,0 +1,458 @@
+use builder;
+use digraph;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path;
+use stream;
+use syntax;
+use syntax::{option, parse_non_negative, parse_probability, parse_value};
+
+/// Name of the file a compiled digraph is stored in within its directory.
+///
+/// The file is line oriented with tab separated fields. It starts with a
+/// header and the audio format, followed by the nodes and arrows of the
+/// digraph. Each arrow is followed by its variants, with file paths relative
+/// to the directory and their lengths in frames:
+///
+/// ```text
+/// digraph123-compiled	1
+/// voice	2	44100
+/// layer	intensity=0.3-1	tempo=120
+/// tag	calm	intensity=0-0.4
+/// node	woods	stay=0.7
+/// arrow	woods	village	weight=2	limit=1	order=random
+/// variant	calm	woods-village-calm.ogg	frames=441000
+/// ```
+pub const FILE_NAME: &'static str = "digraph.compiled";
+
+const HEADER: &'static str = "digraph123-compiled";
+const VERSION: u32 = 1;
+
+fn field<'a>(line: usize,
+             fields: &mut Iterator<Item = &'a str>,
+             what: &str)
+             -> stream::Result<&'a str> {
+    fields.next().ok_or_else(|| syntax::error(line, format!("missing {}", what)))
+}
+
+/// Writes `digraph` with file paths relative to `base`.
+pub fn write<W: Write>(writer: &mut W,
+                       base: &path::Path,
+                       voice_config: builder::VoiceConfig,
+                       digraph: &digraph::Digraph)
+                       -> io::Result<()> {
+    try!(writeln!(writer, "{}\t{}", HEADER, VERSION));
+    try!(writeln!(writer, "voice\t{}\t{}", voice_config.0, voice_config.1));
+    if digraph.intensity().is_some() || digraph.tempo().is_some() {
+        try!(write!(writer, "layer"));
+        if let Some(span) = digraph.intensity() {
+            try!(write!(writer, "\tintensity={}", span));
+        }
+        if let Some(bpm) = digraph.tempo() {
+            try!(write!(writer, "\ttempo={}", bpm));
+        }
+        try!(writeln!(writer, ""));
+    }
+    for (tag, span) in digraph.tag_intensities() {
+        try!(writeln!(writer, "tag\t{}\tintensity={}", tag, span));
+    }
+    // Nodes and arrows are written in order of their names, whatever order
+    // the files of the directory were scanned in.
+    let nodes = digraph.nodes();
+    let mut order: Vec<usize> = (1..nodes.len()).collect();
+    order.sort_by_key(|&i| nodes[i].name());
+    order.insert(0, 0);
+    for node in order.iter().map(|&i| &nodes[i]) {
+        try!(write!(writer, "node\t{}", node.name()));
+        if let Some(stay) = node.stay() {
+            try!(write!(writer, "\tstay={}", stay));
+        }
+        if let Some(min) = node.min_dwell() {
+            try!(write!(writer, "\tmin={}", min));
+        }
+        if let Some(max) = node.max_dwell() {
+            try!(write!(writer, "\tmax={}", max));
+        }
+        try!(writeln!(writer, ""));
+    }
+    for node in order.iter().map(|&i| &nodes[i]) {
+        let mut arrows: Vec<&digraph::Arrow> = node.arrows()
+                                                   .iter()
+                                                   .filter(|arrow| !arrow.variants().is_empty())
+                                                   .collect();
+        arrows.sort_by_key(|arrow| {
+            (nodes[arrow.head()].name(), arrow.role() == digraph::Role::Bridge)
+        });
+        for arrow in arrows {
+            try!(write!(writer,
+                        "arrow\t{}\t{}\tweight={}\torder={}",
+                        node.name(),
+                        nodes[arrow.head()].name(),
+                        arrow.weight(),
+                        arrow.order()));
+            if let Some(limit) = arrow.limit() {
+                try!(write!(writer, "\tlimit={}", limit));
+            }
+            if let Some(span) = arrow.intensity() {
+                try!(write!(writer, "\tintensity={}", span));
+            }
+            if let Some(seconds) = arrow.after() {
+                try!(write!(writer, "\tafter={}", seconds));
+            }
+            if let Some(seconds) = arrow.after_dwell() {
+                try!(write!(writer, "\tdwell={}", seconds));
+            }
+            if let Some(seconds) = arrow.crossfade() {
+                try!(write!(writer, "\tcrossfade={}", seconds));
+            }
+            try!(writeln!(writer, ""));
+            for variant in arrow.variants() {
+                let path = variant.path().strip_prefix(base).unwrap_or(variant.path());
+                try!(write!(writer,
+                            "variant\t{}\t{}",
+                            variant.label().unwrap_or(""),
+                            path.display()));
+                if let Some(frames) = variant.frames() {
+                    try!(write!(writer, "\tframes={}", frames));
+                }
+                try!(writeln!(writer, ""));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a digraph written by `write`, resolving file paths against `base`.
+pub fn read<R: BufRead>(reader: R,
+                        base: &path::Path)
+                        -> stream::Result<(builder::VoiceConfig, digraph::Digraph)> {
+    let mut builder = digraph::DigraphBuilder::new();
+    let mut voice_config = None;
+    let mut arrow: Option<(String, String)> = None;
+    for (i, line) in reader.lines().enumerate() {
+        let line = try!(line);
+        let number = i + 1;
+        let mut fields = line.split('\t');
+        let directive = try!(field(number, &mut fields, "directive"));
+        if number == 1 {
+            let version: u32 = try!(parse_value(number,
+                                                "version",
+                                                try!(field(number, &mut fields, "version"))));
+            if directive != HEADER || version != VERSION {
+                return Err(syntax::error(number, "not a compiled digraph".to_string()));
+            }
+            continue;
+        }
+        match directive {
+            "voice" => {
+                let channels = try!(field(number, &mut fields, "channels"));
+                let rate = try!(field(number, &mut fields, "sample rate"));
+                voice_config = Some((try!(parse_value(number, "channels", channels)),
+                                     try!(parse_value(number, "sample rate", rate))));
+            }
+            "layer" => {
+                for word in fields {
+                    match try!(option(number, word)) {
+                        ("intensity", value) => {
+                            let span = try!(parse_value(number, "intensity", value));
+                            builder.layer_intensity(span);
+                        }
+                        ("tempo", value) => {
+                            builder.tempo(try!(parse_value(number, "tempo", value)));
+                        }
+                        (key, _) => {
+                            return Err(syntax::error(number, format!("unknown option '{}'", key)))
+                        }
+                    }
+                }
+            }
+            "tag" => {
+                let tag = try!(field(number, &mut fields, "tag name"));
+                for word in fields {
+                    match try!(option(number, word)) {
+                        ("intensity", value) => {
+                            let span = try!(parse_value(number, "intensity", value));
+                            builder.tag_intensity(tag, span);
+                        }
+                        (key, _) => {
+                            return Err(syntax::error(number, format!("unknown option '{}'", key)))
+                        }
+                    }
+                }
+            }
+            "node" => {
+                let name = try!(field(number, &mut fields, "node name"));
+                for word in fields {
+                    match try!(option(number, word)) {
+                        ("stay", value) => {
+                            builder.stay(name, try!(parse_probability(number, "stay", value)));
+                        }
+                        ("min", value) => {
+                            builder.min_dwell(name, try!(parse_value(number, "min", value)));
+                        }
+                        ("max", value) => {
+                            builder.max_dwell(name, try!(parse_value(number, "max", value)));
+                        }
+                        (key, _) => {
+                            return Err(syntax::error(number, format!("unknown option '{}'", key)))
+                        }
+                    }
+                }
+                try!(syntax::check_dwell(number, &builder, name));
+            }
+            "arrow" => {
+                let tail = try!(field(number, &mut fields, "arrow tail"));
+                let head = try!(field(number, &mut fields, "arrow head"));
+                for word in fields {
+                    match try!(option(number, word)) {
+                        ("weight", value) => {
+                            let weight = try!(parse_non_negative(number, "weight", value));
+                            builder.weight(tail, head, weight);
+                        }
+                        ("limit", value) => {
+                            builder.limit(tail, head, try!(parse_value(number, "limit", value)));
+                        }
+                        ("order", value) => {
+                            builder.order(tail, head, try!(parse_value(number, "order", value)));
+                        }
+                        ("intensity", value) => {
+                            let span = try!(parse_value(number, "intensity", value));
+                            builder.intensity(tail, head, span);
+                        }
+                        ("after", value) => {
+                            let seconds = try!(parse_non_negative(number, "after", value));
+                            builder.after(tail, head, seconds);
+                        }
+                        ("dwell", value) => {
+                            let seconds = try!(parse_non_negative(number, "dwell", value));
+                            builder.after_dwell(tail, head, seconds);
+                        }
+                        ("crossfade", value) => {
+                            let seconds = try!(parse_non_negative(number, "crossfade", value));
+                            builder.crossfade(tail, head, seconds);
+                        }
+                        (key, _) => {
+                            return Err(syntax::error(number, format!("unknown option '{}'", key)))
+                        }
+                    }
+                }
+                arrow = Some((tail.to_string(), head.to_string()));
+            }
+            "variant" => {
+                let label = try!(field(number, &mut fields, "variant label"));
+                let path = base.join(try!(field(number, &mut fields, "variant path")));
+                let label = if label.is_empty() {
+                    None
+                } else {
+                    Some(label)
+                };
+                let mut frames = None;
+                for word in fields {
+                    match try!(option(number, word)) {
+                        ("frames", value) => {
+                            frames = Some(try!(parse_value(number, "frames", value)));
+                        }
+                        (key, _) => {
+                            return Err(syntax::error(number, format!("unknown option '{}'", key)))
+                        }
+                    }
+                }
+                let variant = digraph::Variant::new(path, label).with_frames(frames);
+                if let Some((ref tail, ref head)) = arrow {
+                    builder.arrow(tail.clone(), head.clone(), variant);
+                } else {
+                    return Err(syntax::error(number, "variant outside of arrow".to_string()));
+                }
+            }
+            _ => return Err(syntax::error(number, format!("unknown directive '{}'", directive))),
+        }
+    }
+    if let Some(voice_config) = voice_config {
+        Ok((voice_config, builder.into()))
+    } else {
+        Err(stream::Error::NoItems)
+    }
+}
+
+/// Compiles the digraph of `dir` into its `FILE_NAME`.
+pub fn save(dir: &path::Path,
+            voice_config: builder::VoiceConfig,
+            digraph: &digraph::Digraph)
+            -> stream::Result<()> {
+    let path = dir.join(FILE_NAME);
+    let mut file = io::BufWriter::new(try!(fs::File::create(&path)));
+    write(&mut file, dir, voice_config, digraph)
+        .and_then(|_| file.flush())
+        .map_err(|err| stream::Error::File(path, Box::new(stream::Error::Io(err))))
+}
+
+/// Whether the directory, or any file in it, changed after the compiled
+/// digraph of `dir` was written.
+pub fn is_outdated(dir: &path::Path) -> io::Result<bool> {
+    let compiled = try!(try!(fs::metadata(dir.join(FILE_NAME))).modified());
+    if try!(try!(fs::metadata(dir)).modified()) > compiled {
+        return Ok(true);
+    }
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        if entry.file_name() != FILE_NAME && try!(try!(entry.metadata()).modified()) > compiled {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Loads the compiled digraph of `dir`, if there is one.
+pub fn load(dir: &path::Path) -> stream::Result<Option<(builder::VoiceConfig, digraph::Digraph)>> {
+    let path = dir.join(FILE_NAME);
+    if path.is_file() {
+        let file = try!(fs::File::open(&path));
+        read(io::BufReader::new(file), dir)
+            .map(Some)
+            .map_err(|err| stream::Error::File(path, Box::new(err)))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digraph::{Digraph, DigraphBuilder, Order, Span, Variant};
+
+    fn digraph(base: &path::Path) -> Digraph {
+        let mut builder = DigraphBuilder::new();
+        builder.arrow("start".to_string(),
+                      "woods".to_string(),
+                      Variant::new(base.join("start-woods.ogg"), None));
+        builder.arrow("woods".to_string(),
+                      "village".to_string(),
+                      Variant::new(base.join("woods-village-1-calm.ogg"), Some("1-calm"))
+                          .with_frames(Some(441000)));
+        builder.stay("woods", 0.7)
+               .min_dwell("woods", 2)
+               .max_dwell("woods", 5)
+               .weight("woods", "village", 2.0)
+               .limit("woods", "village", 1)
+               .order("woods", "village", Order::Once)
+               .intensity("woods",
+                          "village",
+                          Span {
+                              low: 0.6,
+                              high: 1.0,
+                          })
+               .after("woods", "village", 1200.0)
+               .after_dwell("woods", "village", 60.0)
+               .crossfade("woods", "village", 2.5)
+               .tag_intensity("calm",
+                              Span {
+                                  low: 0.0,
+                                  high: 0.4,
+                              })
+               .layer_intensity(Span {
+                   low: 0.3,
+                   high: 1.0,
+               })
+               .tempo(120.0);
+        builder.into()
+    }
+
+    fn read_str(text: &str) -> stream::Result<(builder::VoiceConfig, Digraph)> {
+        read(io::Cursor::new(text.as_bytes()), path::Path::new("/music"))
+    }
+
+    #[test]
+    fn write_then_read_round_trips() {
+        let base = path::Path::new("/music/example");
+        let mut bytes = vec![];
+        write(&mut bytes, base, (2, 44100), &digraph(base)).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.contains("variant\t1-calm\twoods-village-1-calm.ogg\tframes=441000\n"));
+
+        let (voice_config, digraph) = read(io::Cursor::new(text.as_bytes()), base).unwrap();
+        assert_eq!(voice_config, (2, 44100));
+        assert_eq!(digraph.tempo(), Some(120.0));
+        assert_eq!(digraph.intensity().map(|span| span.low), Some(0.3));
+        assert_eq!(digraph.tag_intensities().get("calm").map(|span| span.high), Some(0.4));
+        let woods = &digraph.nodes()[digraph.index("woods").unwrap()];
+        assert_eq!((woods.stay(), woods.min_dwell(), woods.max_dwell()),
+                   (Some(0.7), Some(2), Some(5)));
+        let arrow = &woods.arrows()[0];
+        assert_eq!(digraph.nodes()[arrow.head()].name(), "village");
+        assert_eq!((arrow.weight(), arrow.limit()), (2.0, Some(1)));
+        assert!(arrow.order() == Order::Once);
+        assert_eq!(arrow.intensity().map(|span| span.low), Some(0.6));
+        assert_eq!((arrow.after(), arrow.after_dwell(), arrow.crossfade()),
+                   (Some(1200.0), Some(60.0), Some(2.5)));
+        let variant = &arrow.variants()[0];
+        assert_eq!(variant.path(), base.join("woods-village-1-calm.ogg").as_path());
+        assert_eq!((variant.label(), variant.number()), (Some("1-calm"), Some(1)));
+        assert_eq!(variant.frames(), Some(441000));
+        let start = &digraph.nodes()[0].arrows()[0];
+        assert_eq!(start.variants()[0].frames(), None);
+
+        let mut again = vec![];
+        write(&mut again, base, voice_config, &digraph).unwrap();
+        assert_eq!(String::from_utf8(again).unwrap(), text);
+    }
+
+    #[test]
+    fn read_rejects_other_files() {
+        match read_str("digraph123-compiled\t2\nvoice\t2\t44100\n") {
+            Err(stream::Error::Syntax(1, _)) => {}
+            _ => panic!("expected a syntax error on the header"),
+        }
+        match read_str("digraph123-compiled\t1\nvoice\t2\t44100\nvariant\t\ta.ogg\n") {
+            Err(stream::Error::Syntax(3, _)) => {}
+            _ => panic!("expected a syntax error on the variant"),
+        }
+        match read_str("digraph123-compiled\t1\nnode\twoods\tstay=0.5\n") {
+            Err(stream::Error::NoItems) => {}
+            _ => panic!("expected the voice to be missing"),
+        }
+    }
+
+    #[test]
+    fn write_orders_nodes_and_arrows_by_name() {
+        let base = path::Path::new("/music");
+        let arrows = [("start", "woods"), ("woods", "village"), ("village", "woods"),
+                      ("woods", "castle"), ("start", "village")];
+        let compile = |order: &[usize]| {
+            let mut builder = DigraphBuilder::new();
+            for &i in order {
+                let (tail, head) = arrows[i];
+                let variant = Variant::new(base.join(format!("{}-{}.ogg", tail, head)), None);
+                builder.arrow(tail.to_string(), head.to_string(), variant);
+            }
+            let digraph: Digraph = builder.into();
+            let mut bytes = vec![];
+            write(&mut bytes, base, (2, 44100), &digraph).unwrap();
+            String::from_utf8(bytes).unwrap()
+        };
+        let text = compile(&[0, 1, 2, 3, 4]);
+        assert_eq!(compile(&[4, 3, 2, 1, 0]), text);
+        assert_eq!(compile(&[2, 4, 0, 3, 1]), text);
+        let nodes: Vec<&str> = text.lines()
+                                   .filter(|line| line.starts_with("node\t"))
+                                   .map(|line| &line[5..])
+                                   .collect();
+        assert_eq!(nodes, vec!["start", "castle", "village", "woods"]);
+    }
+
+    #[test]
+    fn read_checks_values_like_the_manifest() {
+        let header = "digraph123-compiled\t1\nvoice\t2\t44100\n";
+        for line in &["node\twoods\tstay=1.5",
+                      "node\twoods\tmin=3\tmax=2",
+                      "arrow\twoods\tvillage\tweight=-1",
+                      "arrow\twoods\tvillage\tafter=-5",
+                      "arrow\twoods\tvillage\tcrossfade=-2.5"] {
+            match read_str(&format!("{}{}\n", header, line)) {
+                Err(stream::Error::Syntax(3, _)) => {}
+                _ => panic!("expected a syntax error on '{}'", line),
+            }
+        }
+    }
+}
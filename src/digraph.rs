@@ -1,23 +1,482 @@
+use history;
 use rand;
 use rand::Rng;
+use std::cell;
+use std::cmp;
 use std::collections;
+use std::fmt;
 use std::path;
+use std::ptr;
+use std::rc;
+use std::str::FromStr;
+use stream;
+use timeline;
 
-pub struct Digraph(Vec<Vec<(usize, Vec<path::PathBuf>)>>);
+pub type Tags = collections::BTreeSet<String>;
+
+pub fn parse_tags(label: &str) -> Tags {
+    label.split(|c| c == '-' || c == ',')
+         .filter(|tag| !tag.is_empty())
+         .map(|tag| tag.to_lowercase())
+         .collect()
+}
+
+#[derive(Clone)]
+pub struct Variant {
+    path: path::PathBuf,
+    stems: Vec<path::PathBuf>,
+    frames: Option<u64>,
+    label: Option<String>,
+    number: Option<u64>,
+    tags: Tags,
+}
+
+impl Variant {
+    /// A leading number in the label gives the variant its place in
+    /// sequential ordering, and the rest of the label is split into tags.
+    pub fn new(path: path::PathBuf, label: Option<&str>) -> Variant {
+        let number = label.and_then(|label| label.split('-').next())
+                          .and_then(|first| u64::from_str(first).ok());
+        let tags = match (label, number) {
+            (Some(label), Some(_)) => parse_tags(label.splitn(2, '-').nth(1).unwrap_or("")),
+            (Some(label), None) => parse_tags(label),
+            (None, _) => Tags::new(),
+        };
+        Variant {
+            path: path,
+            stems: vec![],
+            frames: None,
+            label: label.map(|label| label.to_string()),
+            number: number,
+            tags: tags,
+        }
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// The files of the variant in the other stems of its layer.
+    pub fn stems(&self) -> &[path::PathBuf] {
+        &self.stems
+    }
+
+    /// Sets the length of the file in frames, as read when scanning it.
+    pub fn with_frames(mut self, frames: Option<u64>) -> Variant {
+        self.frames = frames;
+        self
+    }
+
+    /// The length in frames of the shortest file of the variant, if known.
+    pub fn frames(&self) -> Option<u64> {
+        self.frames
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_ref().map(String::as_str)
+    }
+
+    pub fn number(&self) -> Option<u64> {
+        self.number
+    }
+
+    pub fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    fn step(&self, tail: &str, head: &str, crossfade: Option<f64>) -> Step {
+        Step {
+            tail: tail.to_string(),
+            head: head.to_string(),
+            label: self.label.clone(),
+            paths: Some(self.path.clone()).into_iter().chain(self.stems.iter().cloned()).collect(),
+            frames: self.frames,
+            crossfade: crossfade,
+        }
+    }
+}
+
+/// How the variants of an arrow are chosen among.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Order {
+    /// Pick a variant at random.
+    Random,
+    /// Play the variants in order and start over after the last one.
+    Cycle,
+    /// Play the variants in order, after which the arrow is exhausted.
+    Once,
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Order::Random => write!(f, "random"),
+            Order::Cycle => write!(f, "cycle"),
+            Order::Once => write!(f, "once"),
+        }
+    }
+}
+
+impl FromStr for Order {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Order, ()> {
+        match s {
+            "random" => Ok(Order::Random),
+            "cycle" => Ok(Order::Cycle),
+            "once" => Ok(Order::Once),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A closed range of intensities, written "LOW-HIGH", e.g. "0.5-1".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub low: f64,
+    pub high: f64,
+}
+
+impl Span {
+    pub fn contains(&self, intensity: f64) -> bool {
+        self.low <= intensity && intensity <= self.high
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.low, self.high)
+    }
+}
+
+impl FromStr for Span {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Span, ()> {
+        let mut parts = s.splitn(2, '-');
+        let low = try!(parts.next().and_then(|low| f64::from_str(low).ok()).ok_or(()));
+        let high = try!(parts.next().and_then(|high| f64::from_str(high).ok()).ok_or(()));
+        if 0.0 <= low && low <= high && high <= 1.0 {
+            Ok(Span {
+                low: low,
+                high: high,
+            })
+        } else {
+            Err(())
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ArrowSettings {
+    weight: f64,
+    limit: Option<usize>,
+    order: Order,
+    intensity: Option<Span>,
+    after: Option<f64>,
+    after_dwell: Option<f64>,
+    crossfade: Option<f64>,
+}
+
+impl Default for ArrowSettings {
+    fn default() -> ArrowSettings {
+        ArrowSettings {
+            weight: 1.0,
+            limit: None,
+            order: Order::Random,
+            intensity: None,
+            after: None,
+            after_dwell: None,
+            crossfade: None,
+        }
+    }
+}
+
+/// Tag marking the variants of bridge arrows.
+pub const BRIDGE_TAG: &'static str = "bridge";
+
+/// What an arrow is taken for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// The walk takes the arrow at random.
+    Ambient,
+    /// The walk only takes the arrow on its way to a requested node, and
+    /// then prefers it over ambient arrows.
+    Bridge,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Role::Ambient => write!(f, "ambient"),
+            Role::Bridge => write!(f, "bridge"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Arrow {
+    head: usize,
+    role: Role,
+    settings: ArrowSettings,
+    variants: Vec<Variant>,
+}
+
+impl Arrow {
+    /// Index of the node the arrow points to.
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.settings.weight
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.settings.limit
+    }
+
+    pub fn order(&self) -> Order {
+        self.settings.order
+    }
+
+    /// The intensities at which the arrow may be taken, if restricted.
+    pub fn intensity(&self) -> Option<Span> {
+        self.settings.intensity
+    }
+
+    /// Seconds of playback after which the arrow may be taken.
+    pub fn after(&self) -> Option<f64> {
+        self.settings.after
+    }
+
+    /// Seconds of playback in its tail after which the arrow may be taken.
+    pub fn after_dwell(&self) -> Option<f64> {
+        self.settings.after_dwell
+    }
+
+    /// Seconds over which the tracks of the arrow crossfade into the next.
+    pub fn crossfade(&self) -> Option<f64> {
+        self.settings.crossfade
+    }
+
+    /// The variants of the arrow. Arrows without variants are silent.
+    pub fn variants(&self) -> &[Variant] {
+        &self.variants
+    }
+}
+
+#[derive(Clone, Default)]
+struct NodeSettings {
+    stay: Option<f64>,
+    min_dwell: Option<usize>,
+    max_dwell: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct Node {
+    name: String,
+    settings: NodeSettings,
+    arrows: Vec<Arrow>,
+}
+
+impl Node {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn stay(&self) -> Option<f64> {
+        self.settings.stay
+    }
+
+    pub fn min_dwell(&self) -> Option<usize> {
+        self.settings.min_dwell
+    }
+
+    pub fn max_dwell(&self) -> Option<usize> {
+        self.settings.max_dwell
+    }
+
+    pub fn arrows(&self) -> &[Arrow] {
+        &self.arrows
+    }
+}
+
+#[derive(Clone)]
+pub struct Digraph {
+    nodes: Vec<Node>,
+    tag_intensities: collections::BTreeMap<String, Span>,
+    intensity: Option<Span>,
+    tempo: Option<f64>,
+}
 
 impl Digraph {
-    pub fn into_random_walk(self, rng: Box<rand::Rng>) -> IntoRandomWalk {
-        IntoRandomWalk {
-            state: 0,
-            digraph: self,
-            rng: rng,
+    /// Number of arrows on a shortest path from each node to `target`, if
+    /// there is a path.
+    fn distances_to(&self, target: usize) -> Vec<Option<usize>> {
+        let mut distances = vec![None; self.nodes.len()];
+        distances[target] = Some(0);
+        let mut queue = collections::VecDeque::new();
+        queue.push_back(target);
+        while let Some(head) = queue.pop_front() {
+            let distance = distances[head].map(|d| d + 1);
+            for (tail, node) in self.nodes.iter().enumerate() {
+                if distances[tail].is_none() && node.arrows.iter().any(|a| a.head == head) {
+                    distances[tail] = distance;
+                    queue.push_back(tail);
+                }
+            }
+        }
+        distances
+    }
+
+    /// The nodes of the digraph, starting with "start".
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.name == name)
+    }
+
+    /// The files of every variant, including those in other stems.
+    pub fn files(&self) -> Vec<&path::Path> {
+        self.nodes
+            .iter()
+            .flat_map(|node| node.arrows.iter())
+            .flat_map(|arrow| arrow.variants.iter())
+            .flat_map(|variant| Some(&variant.path).into_iter().chain(variant.stems.iter()))
+            .map(|path| path.as_path())
+            .collect()
+    }
+
+    /// The intensities at which variants carrying each tag may be played.
+    pub fn tag_intensities(&self) -> &collections::BTreeMap<String, Span> {
+        &self.tag_intensities
+    }
+
+    /// The intensities at which the layer playing the digraph is heard.
+    pub fn intensity(&self) -> Option<Span> {
+        self.intensity
+    }
+
+    /// Beats per minute of the music, if known.
+    pub fn tempo(&self) -> Option<f64> {
+        self.tempo
+    }
+
+    /// Every pair of steps where the second may follow the first, i.e. each
+    /// arrow into a node followed by each arrow out of it.
+    ///
+    /// Silent arrows are left out. Each arrow is represented by its first
+    /// variant, or with `every_variant` by each of its variants in turn.
+    pub fn seams(&self, every_variant: bool) -> Vec<(Step, Step)> {
+        let steps = |node: &Node| -> Vec<Step> {
+            node.arrows
+                .iter()
+                .flat_map(|arrow| {
+                    let count = if every_variant {
+                        arrow.variants.len()
+                    } else {
+                        1
+                    };
+                    let head = &self.nodes[arrow.head].name;
+                    arrow.variants
+                         .iter()
+                         .take(count)
+                         .map(move |v| v.step(&node.name, head, arrow.settings.crossfade))
+                })
+                .collect()
+        };
+        let outgoing: Vec<Vec<Step>> = self.nodes.iter().map(steps).collect();
+        let mut seams = vec![];
+        for steps in outgoing.iter() {
+            for first in steps.iter() {
+                let next = self.index(&first.head).map_or(&[][..], |i| &outgoing[i][..]);
+                for second in next {
+                    seams.push((first.clone(), second.clone()));
+                }
+            }
         }
+        seams
+    }
+
+    /// Merges the digraph of another stem into this one.
+    ///
+    /// Both digraphs must have the same nodes, arrows and variant labels.
+    /// Afterwards every variant also refers to its file in `other`.
+    pub fn stack(mut self, other: Digraph) -> stream::Result<Digraph> {
+        fn mismatch(message: String) -> stream::Error {
+            stream::Error::Mismatch(message)
+        }
+
+        if self.nodes.len() != other.nodes.len() {
+            return Err(mismatch("different sets of nodes".to_string()));
+        }
+        let names: Vec<String> = self.nodes.iter().map(|node| node.name.clone()).collect();
+        for node in self.nodes.iter_mut() {
+            let tail = &node.name;
+            let other_node = try!(other.index(tail)
+                                       .map(|i| &other.nodes[i])
+                                       .ok_or_else(|| mismatch(format!("no node '{}'", tail))));
+            if node.arrows.len() != other_node.arrows.len() {
+                return Err(mismatch(format!("different arrows from '{}'", tail)));
+            }
+            for arrow in node.arrows.iter_mut() {
+                let head = &names[arrow.head];
+                let other_arrow = other_node.arrows
+                                            .iter()
+                                            .find(|a| {
+                                                &other.nodes[a.head].name == head &&
+                                                a.role == arrow.role
+                                            });
+                let other_arrow = try!(other_arrow.ok_or_else(|| {
+                    mismatch(format!("no arrow from '{}' to '{}'", tail, head))
+                }));
+                if arrow.variants.len() != other_arrow.variants.len() {
+                    return Err(mismatch(format!("different variants from '{}' to '{}'",
+                                                tail,
+                                                head)));
+                }
+                for variant in arrow.variants.iter_mut() {
+                    let other_variant = other_arrow.variants
+                                                   .iter()
+                                                   .find(|v| v.label == variant.label);
+                    let other_variant = try!(other_variant.ok_or_else(|| {
+                        mismatch(format!("no variant of '{}'", variant.path.display()))
+                    }));
+                    variant.stems.push(other_variant.path.clone());
+                    variant.stems.extend(other_variant.stems.iter().cloned());
+                    variant.frames = match (variant.frames, other_variant.frames) {
+                        (Some(frames), Some(other)) => Some(cmp::min(frames, other)),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        for (tag, span) in other.tag_intensities {
+            self.tag_intensities.entry(tag).or_insert(span);
+        }
+        self.intensity = self.intensity.or(other.intensity);
+        self.tempo = self.tempo.or(other.tempo);
+        Ok(self)
+    }
+
+    pub fn into_random_walk(self, rng: Box<rand::Rng>, steering: Steering) -> IntoRandomWalk {
+        IntoRandomWalk::new(rc::Rc::new(self), rng, steering)
     }
 }
 
 pub struct DigraphBuilder {
     indices: collections::HashMap<String, usize>,
-    arrows: collections::HashMap<(usize, usize), Vec<path::PathBuf>>,
+    arrows: collections::HashMap<(usize, usize, Role), Vec<Variant>>,
+    nodes: collections::HashMap<String, NodeSettings>,
+    arrow_settings: collections::HashMap<(String, String), ArrowSettings>,
+    tag_intensities: collections::BTreeMap<String, Span>,
+    intensity: Option<Span>,
+    tempo: Option<f64>,
 }
 
 impl DigraphBuilder {
@@ -27,62 +486,958 @@ impl DigraphBuilder {
         DigraphBuilder {
             indices: indices,
             arrows: collections::HashMap::new(),
+            nodes: collections::HashMap::new(),
+            arrow_settings: collections::HashMap::new(),
+            tag_intensities: collections::BTreeMap::new(),
+            intensity: None,
+            tempo: None,
         }
     }
-    pub fn arrow(&mut self, tail: String, head: String, path: path::PathBuf) -> &mut Self {
+    /// Adds a variant to the arrow from `tail` to `head`.
+    ///
+    /// Variants tagged `BRIDGE_TAG` go to a separate bridge arrow, and the
+    /// tag is not used for restricting variants.
+    pub fn arrow(&mut self, tail: String, head: String, mut variant: Variant) -> &mut Self {
         let next_index = self.indices.len();
         let tail = *self.indices.entry(tail).or_insert(next_index);
         let next_index = self.indices.len();
         let head = *self.indices.entry(head).or_insert(next_index);
+        let role = if variant.tags.remove(BRIDGE_TAG) {
+            Role::Bridge
+        } else {
+            Role::Ambient
+        };
         self.arrows
-            .entry((tail, head))
+            .entry((tail, head, role))
             .or_insert_with(|| vec![])
-            .push(path);
+            .push(variant);
+        self
+    }
+
+    /// Sets the probability of taking a self-loop out of `node` rather than
+    /// one of its other exits.
+    pub fn stay(&mut self, node: &str, probability: f64) -> &mut Self {
+        self.nodes.entry(node.to_string()).or_insert_with(Default::default).stay =
+            Some(probability);
+        self
+    }
+
+    /// Sets the number of times the walk must take a self-loop of `node`
+    /// before it may take one of the other exits.
+    pub fn min_dwell(&mut self, node: &str, count: usize) -> &mut Self {
+        self.nodes.entry(node.to_string()).or_insert_with(Default::default).min_dwell =
+            Some(count);
+        self
+    }
+
+    /// Sets the number of times the walk may take a self-loop of `node`
+    /// before it must take one of the other exits.
+    pub fn max_dwell(&mut self, node: &str, count: usize) -> &mut Self {
+        self.nodes.entry(node.to_string()).or_insert_with(Default::default).max_dwell =
+            Some(count);
+        self
+    }
+
+    /// The minimum and maximum dwell counts set for `node` so far.
+    pub fn dwell(&self, node: &str) -> (Option<usize>, Option<usize>) {
+        self.nodes.get(node).map_or((None, None), |node| (node.min_dwell, node.max_dwell))
+    }
+
+    /// Sets the relative weight of the arrow from `tail` to `head`. The
+    /// default weight is 1.
+    pub fn weight(&mut self, tail: &str, head: &str, weight: f64) -> &mut Self {
+        self.arrow_settings
+            .entry((tail.to_string(), head.to_string()))
+            .or_insert_with(Default::default)
+            .weight = weight;
+        self
+    }
+
+    /// Sets the number of times the walk may take the arrow from `tail` to
+    /// `head` during a session.
+    pub fn limit(&mut self, tail: &str, head: &str, count: usize) -> &mut Self {
+        self.arrow_settings
+            .entry((tail.to_string(), head.to_string()))
+            .or_insert_with(Default::default)
+            .limit = Some(count);
+        self
+    }
+
+    /// Sets how the walk chooses among the variants of the arrow from `tail`
+    /// to `head`.
+    pub fn order(&mut self, tail: &str, head: &str, order: Order) -> &mut Self {
+        self.arrow_settings
+            .entry((tail.to_string(), head.to_string()))
+            .or_insert_with(Default::default)
+            .order = order;
+        self
+    }
+
+    /// Restricts the arrow from `tail` to `head` to the intensities in
+    /// `span`.
+    pub fn intensity(&mut self, tail: &str, head: &str, span: Span) -> &mut Self {
+        self.arrow_settings
+            .entry((tail.to_string(), head.to_string()))
+            .or_insert_with(Default::default)
+            .intensity = Some(span);
+        self
+    }
+
+    /// Keeps the walk from taking the arrow from `tail` to `head` until
+    /// `seconds` of the session have been played.
+    pub fn after(&mut self, tail: &str, head: &str, seconds: f64) -> &mut Self {
+        self.arrow_settings
+            .entry((tail.to_string(), head.to_string()))
+            .or_insert_with(Default::default)
+            .after = Some(seconds);
+        self
+    }
+
+    /// Keeps the walk from taking the arrow from `tail` to `head` until
+    /// `seconds` have been played since it entered `tail`.
+    pub fn after_dwell(&mut self, tail: &str, head: &str, seconds: f64) -> &mut Self {
+        self.arrow_settings
+            .entry((tail.to_string(), head.to_string()))
+            .or_insert_with(Default::default)
+            .after_dwell = Some(seconds);
+        self
+    }
+
+    /// Makes the tracks of the arrow from `tail` to `head` crossfade into the
+    /// next over `seconds`.
+    pub fn crossfade(&mut self, tail: &str, head: &str, seconds: f64) -> &mut Self {
+        self.arrow_settings
+            .entry((tail.to_string(), head.to_string()))
+            .or_insert_with(Default::default)
+            .crossfade = Some(seconds);
+        self
+    }
+
+    /// Restricts variants carrying `tag` to the intensities in `span`.
+    pub fn tag_intensity(&mut self, tag: &str, span: Span) -> &mut Self {
+        self.tag_intensities.insert(tag.to_lowercase(), span);
+        self
+    }
+
+    /// Silences the layer playing the digraph outside the intensities in
+    /// `span`.
+    pub fn layer_intensity(&mut self, span: Span) -> &mut Self {
+        self.intensity = Some(span);
+        self
+    }
+
+    /// Sets the tempo in beats per minute, for quantizing stingers to beats.
+    pub fn tempo(&mut self, bpm: f64) -> &mut Self {
+        self.tempo = Some(bpm);
         self
     }
 }
 
 impl Into<Digraph> for DigraphBuilder {
     fn into(self) -> Digraph {
-        let mut digraph = Vec::with_capacity(self.indices.len());
-        for _ in 0..self.indices.len() {
-            digraph.push(vec![]);
+        let mut names: Vec<_> = self.indices.into_iter().collect();
+        names.sort_by_key(|&(_, index)| index);
+        let nodes = self.nodes;
+        let mut digraph: Vec<_> = names.into_iter()
+                                       .map(|(name, _)| {
+                                           Node {
+                                               settings: nodes.get(&name)
+                                                              .cloned()
+                                                              .unwrap_or_default(),
+                                               name: name,
+                                               arrows: vec![],
+                                           }
+                                       })
+                                       .collect();
+        for ((tail, head, role), mut variants) in self.arrows {
+            // Numbered variants come first in numerical order.
+            variants.sort_by(|a, b| {
+                let a = (a.number.is_none(), a.number, &a.path);
+                let b = (b.number.is_none(), b.number, &b.path);
+                a.cmp(&b)
+            });
+            let key = (digraph[tail].name.clone(), digraph[head].name.clone());
+            digraph[tail].arrows.push(Arrow {
+                head: head,
+                role: role,
+                settings: self.arrow_settings.get(&key).cloned().unwrap_or_default(),
+                variants: variants,
+            });
         }
-        for ((tail, head), arrows) in self.arrows {
-            digraph[tail].push((head, arrows));
+        if digraph[0].arrows.len() == 0 {
+            for i in 1..digraph.len() {
+                digraph[0].arrows.push(Arrow {
+                    head: i,
+                    role: Role::Ambient,
+                    settings: Default::default(),
+                    variants: vec![],
+                });
+            }
         }
-        if digraph[0].len() == 0 {
-            for i in 1..self.indices.len() {
-                digraph[0].push((i, vec![]));
+        Digraph {
+            nodes: digraph,
+            tag_intensities: self.tag_intensities,
+            intensity: self.intensity,
+            tempo: self.tempo,
+        }
+    }
+}
+
+/// A move of a walk asked for through its steering.
+enum Relocation {
+    /// Back to "start", forgetting the walk so far.
+    Reset,
+    /// Over to the named node.
+    Jump(String),
+}
+
+/// Runtime controls shared between a walk and whoever steers it.
+#[derive(Clone, Default)]
+pub struct Steering(rc::Rc<cell::RefCell<SteeringState>>);
+
+struct SteeringState {
+    tags: Option<Tags>,
+    intensity: Option<f64>,
+    gain: rc::Rc<cell::Cell<f32>>,
+    clock: Option<(rc::Rc<cell::Cell<u64>>, f64)>,
+    digraph: Option<rc::Rc<Digraph>>,
+    relocation: Option<Relocation>,
+    target: Option<String>,
+    couplings: Vec<(String, rc::Weak<cell::RefCell<SteeringState>>, String)>,
+}
+
+impl Default for SteeringState {
+    fn default() -> SteeringState {
+        SteeringState {
+            tags: None,
+            intensity: None,
+            gain: rc::Rc::new(cell::Cell::new(1.0)),
+            clock: None,
+            digraph: None,
+            relocation: None,
+            target: None,
+            couplings: vec![],
+        }
+    }
+}
+
+impl Steering {
+    pub fn new() -> Steering {
+        Steering::default()
+    }
+
+    /// Restricts the walk to variants carrying at least one of the given tags.
+    ///
+    /// Untagged variants are always admitted. `None` lifts the restriction.
+    pub fn set_tags(&self, tags: Option<Tags>) {
+        self.0.borrow_mut().tags = tags;
+    }
+
+    /// Sets the intensity, between 0 and 1, that the walk adapts to.
+    ///
+    /// From its next step on, the walk only takes arrows and plays variants
+    /// whose intensity ranges contain it, and the layer fades to silence if
+    /// its own range does not. `None` lifts every intensity restriction.
+    pub fn set_intensity(&self, intensity: Option<f64>) {
+        self.0.borrow_mut().intensity = intensity;
+    }
+
+    pub fn intensity(&self) -> Option<f64> {
+        self.0.borrow().intensity
+    }
+
+    /// The level, 0 or 1, the layer is fading towards.
+    pub fn gain(&self) -> rc::Rc<cell::Cell<f32>> {
+        self.0.borrow().gain.clone()
+    }
+
+    /// Lets the walk tell the time by a count of samples played at
+    /// `samples_per_second`. Without a clock, no time passes.
+    pub fn set_clock(&self, clock: rc::Rc<cell::Cell<u64>>, samples_per_second: f64) {
+        self.0.borrow_mut().clock = Some((clock, samples_per_second));
+    }
+
+    /// Seconds played according to the clock.
+    pub fn elapsed(&self) -> f64 {
+        self.0.borrow().elapsed()
+    }
+
+    /// Hands the walk a new digraph to continue in.
+    ///
+    /// The walk picks it up at its next step and carries on from the node
+    /// with the same name, or from "start" if there is no such node.
+    pub fn replace_digraph<D: Into<rc::Rc<Digraph>>>(&self, digraph: D) {
+        self.0.borrow_mut().digraph = Some(digraph.into());
+    }
+
+    /// Sends the walk back to "start" as if it had just begun, with its
+    /// limits, orders and dwell counts starting over and no node requested.
+    /// The walk moves at its next step, entering "start" as a jump enters
+    /// its node.
+    pub fn reset(&self) {
+        self.0.borrow_mut().relocation = Some(Relocation::Reset);
+    }
+
+    /// Moves the walk over to `node`, as if it had just entered it. The walk
+    /// moves at its next step, and stays put if there is no such node.
+    pub fn jump(&self, node: &str) {
+        self.0.borrow_mut().relocation = Some(Relocation::Jump(node.to_string()));
+    }
+
+    /// Asks the walk to head for a node, taking the arrows on a shortest path
+    /// to it from its next step on. The request is dropped once the node is
+    /// entered. `None` withdraws the request.
+    pub fn request(&self, node: Option<String>) {
+        self.0.borrow_mut().target = node;
+    }
+
+    pub fn target(&self) -> Option<String> {
+        self.0.borrow().target.clone()
+    }
+
+    /// Makes `target` head for `target_node` whenever this walk enters `node`.
+    pub fn couple(&self, node: &str, target: &Steering, target_node: &str) {
+        self.0.borrow_mut().couplings.push((node.to_string(),
+                                            rc::Rc::downgrade(&target.0),
+                                            target_node.to_string()));
+    }
+
+    /// Drops the request for `node` now that the walk is there.
+    fn settle(&self, node: &str) {
+        let mut state = self.0.borrow_mut();
+        if state.target.as_ref().map_or(false, |target| target == node) {
+            state.target = None;
+        }
+    }
+
+    /// Passes requests on to the walks coupled to entering `node`.
+    fn enter(&self, node: &str) {
+        let requests: Vec<_> = {
+            let state = self.0.borrow();
+            state.couplings
+                 .iter()
+                 .filter(|&&(ref source_node, _, _)| source_node == node)
+                 .filter_map(|&(_, ref target, ref target_node)| {
+                     target.upgrade().map(|target| (target, target_node.clone()))
+                 })
+                 .collect()
+        };
+        for (target, target_node) in requests {
+            target.borrow_mut().target = Some(target_node);
+        }
+    }
+}
+
+impl SteeringState {
+    fn admits(&self,
+              variant: &Variant,
+              tag_intensities: &collections::BTreeMap<String, Span>)
+              -> bool {
+        let tagged = match self.tags {
+            Some(ref tags) => variant.tags.is_empty() || !variant.tags.is_disjoint(tags),
+            None => true,
+        };
+        // Variants without any ranged tag are played at every intensity.
+        let mut spans = variant.tags.iter().filter_map(|tag| tag_intensities.get(tag)).peekable();
+        let ranged = spans.peek().is_none() ||
+                     self.intensity.map_or(true, |i| spans.any(|span| span.contains(i)));
+        tagged && ranged
+    }
+
+    fn elapsed(&self) -> f64 {
+        match self.clock {
+            Some((ref clock, samples_per_second)) if samples_per_second > 0.0 => {
+                clock.get() as f64 / samples_per_second
             }
+            _ => 0.0,
         }
-        Digraph(digraph)
     }
+
+    fn within(&self, span: Option<Span>) -> bool {
+        match (span, self.intensity) {
+            (Some(span), Some(intensity)) => span.contains(intensity),
+            _ => true,
+        }
+    }
+}
+
+/// A step of a walk: the arrow taken and the files of the variant chosen.
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub tail: String,
+    pub head: String,
+    pub label: Option<String>,
+    /// The file of the variant followed by its files in any other stems.
+    pub paths: Vec<path::PathBuf>,
+    /// The length in frames of the shortest of the files, if known.
+    pub frames: Option<u64>,
+    /// Seconds over which to crossfade into the next step, unless the files
+    /// say otherwise.
+    pub crossfade: Option<f64>,
 }
 
 pub struct IntoRandomWalk {
     state: usize,
-    digraph: Digraph,
+    dwell: usize,
+    entered: f64,
+    uses: collections::HashMap<(String, String, Role), usize>,
+    positions: collections::HashMap<(String, String, Role), usize>,
+    digraph: rc::Rc<Digraph>,
     rng: Box<rand::Rng>,
+    steering: Steering,
+    history: Option<history::History>,
+    timeline: Option<(timeline::Timeline, String, f64, u32)>,
+    crossfade: Option<f64>,
+}
+
+/// Keeps the cells satisfying `keep`, unless that would leave none.
+fn narrow<T, F>(cells: &mut Vec<T>, mut keep: F)
+    where F: FnMut(&T) -> bool
+{
+    if cells.iter().any(&mut keep) {
+        cells.retain(keep);
+    }
+}
+
+/// Number of earlier plays of a variant, according to `history`.
+fn plays(history: &Option<history::History>, variant: &Variant) -> f64 {
+    history.as_ref().map_or(0.0, |history| history.plays(&variant.path) as f64)
+}
+
+fn choose_weighted<'a, R, T, F>(rng: &mut R, items: &'a [T], weight: F) -> Option<&'a T>
+    where R: Rng,
+          F: Fn(&T) -> f64
+{
+    let total = items.iter().map(&weight).fold(0.0, |acc, w| acc + w);
+    if total > 0.0 {
+        let mut x = rng.next_f64() * total;
+        for item in items {
+            let w = weight(item);
+            if x < w {
+                return Some(item);
+            }
+            x -= w;
+        }
+        items.iter().rev().find(|item| weight(item) > 0.0)
+    } else {
+        rng.choose(items)
+    }
 }
 
 impl IntoRandomWalk {
-    fn next_once(&mut self) -> Option<&path::Path> {
-        let ref mut rng = self.rng;
-        let cells = self.digraph.0.get(self.state);
-        if let Some(&(new_state, ref arrows)) = cells.and_then(|cells| rng.choose(cells)) {
-            self.state = new_state;
-            rng.choose(arrows.as_slice()).map(|path| path.as_path())
+    /// A walk over `digraph`, which may be shared with other walks.
+    pub fn new(digraph: rc::Rc<Digraph>,
+               rng: Box<rand::Rng>,
+               steering: Steering)
+               -> IntoRandomWalk {
+        IntoRandomWalk {
+            state: 0,
+            dwell: 0,
+            entered: 0.0,
+            uses: collections::HashMap::new(),
+            positions: collections::HashMap::new(),
+            digraph: digraph,
+            rng: rng,
+            steering: steering,
+            history: None,
+            timeline: None,
+            crossfade: None,
+        }
+    }
+
+    /// Biases the walk toward arrows and variants that have been played
+    /// fewer times according to `history`, and records every step in it.
+    ///
+    /// Arrows are weighted by the inverse of one plus the number of times
+    /// they have been taken, and variants by the inverse of one plus their
+    /// own number of plays.
+    pub fn set_history(&mut self, history: history::History) {
+        // Identify the files up front rather than while playing.
+        for file in self.digraph.files() {
+            history.plays(file);
+        }
+        self.history = Some(history);
+    }
+
+    /// Records every step in `timeline` as played by `layer`, at the time
+    /// told by the clock of the steering plus `offset` seconds, with the
+    /// files of the steps played at `rate` frames per second.
+    pub fn set_timeline(&mut self,
+                        timeline: timeline::Timeline,
+                        layer: &str,
+                        offset: f64,
+                        rate: u32) {
+        self.timeline = Some((timeline, layer.to_string(), offset, rate));
+    }
+
+    /// Crossfades each step into the next over `seconds`, unless its arrow
+    /// or files say otherwise.
+    pub fn set_crossfade(&mut self, seconds: Option<f64>) {
+        self.crossfade = seconds;
+    }
+
+    pub fn steering(&self) -> &Steering {
+        &self.steering
+    }
+
+    fn next_once(&mut self) -> Option<Step> {
+        let IntoRandomWalk { ref mut state,
+                             ref mut dwell,
+                             ref entered,
+                             ref mut uses,
+                             ref mut positions,
+                             ref digraph,
+                             ref mut rng,
+                             ref steering,
+                             ref history,
+                             ref crossfade,
+                             .. } = *self;
+        let steering = steering.0.borrow();
+        let node = if let Some(node) = digraph.nodes.get(*state) {
+            node
+        } else {
+            return None;
+        };
+
+        let mut cells: Vec<(&Arrow, Vec<&Variant>)> =
+            node.arrows.iter().map(|arrow| (arrow, arrow.variants.iter().collect())).collect();
+
+        // Distances to the requested node, if there is a way to it from here.
+        let here = *state;
+        let distances = steering.target
+                                .as_ref()
+                                .and_then(|name| digraph.index(name))
+                                .into_iter()
+                                .filter(|&target| target != here)
+                                .map(|target| digraph.distances_to(target))
+                                .filter(|distances| distances[here].is_some())
+                                .next();
+
+        if distances.is_none() {
+            narrow(&mut cells, |&(arrow, _)| arrow.role != Role::Bridge);
+        }
+
+        // Arrows outside the intensity and arrows whose every variant is
+        // filtered out are dropped, unless that would leave the walk stranded.
+        let admitted: Vec<_> = cells.iter()
+                                    .map(|&(arrow, ref variants)| {
+                                        (arrow,
+                                         variants.iter()
+                                                 .cloned()
+                                                 .filter(|v| {
+                                                     steering.admits(v, &digraph.tag_intensities)
+                                                 })
+                                                 .collect::<Vec<_>>())
+                                    })
+                                    .filter(|&(arrow, ref variants)| {
+                                        steering.within(arrow.settings.intensity) &&
+                                        (!variants.is_empty() || arrow.variants.is_empty())
+                                    })
+                                    .collect();
+        if !admitted.is_empty() {
+            cells = admitted;
+        }
+
+        let arrow_key = |arrow: &Arrow| {
+            (node.name.clone(), digraph.nodes[arrow.head].name.clone(), arrow.role)
+        };
+
+        // Exhausted arrows are only considered again once every exit of the
+        // node is exhausted.
+        narrow(&mut cells, |&(arrow, _)| {
+            let key = arrow_key(arrow);
+            let used_up = arrow.settings.limit.map_or(false, |limit| {
+                uses.get(&key).cloned().unwrap_or(0) >= limit
+            });
+            let played_through = arrow.settings.order == Order::Once &&
+                                 positions.get(&key).cloned().unwrap_or(0) >=
+                                 arrow.variants.len();
+            !used_up && !played_through
+        });
+
+        // Arrows are locked until their time has come, unless every exit of
+        // the node is.
+        let now = steering.elapsed();
+        narrow(&mut cells, |&(arrow, _)| {
+            arrow.settings.after.map_or(true, |after| now >= after) &&
+            arrow.settings.after_dwell.map_or(true, |after| now - *entered >= after)
+        });
+
+        if let Some(ref distances) = distances {
+            let nearest = cells.iter().filter_map(|&(arrow, _)| distances[arrow.head]).min();
+            if nearest.is_some() {
+                narrow(&mut cells, |&(arrow, _)| distances[arrow.head] == nearest);
+            }
+            narrow(&mut cells, |&(arrow, _)| arrow.role == Role::Bridge);
+        }
+
+        if node.settings.min_dwell.map_or(false, |min| *dwell < min) {
+            narrow(&mut cells, |&(arrow, _)| arrow.head == here);
+        }
+        if node.settings.max_dwell.map_or(false, |max| *dwell >= max) {
+            narrow(&mut cells, |&(arrow, _)| arrow.head != here);
+        }
+
+        if let Some(stay) = node.settings.stay {
+            let stays = rng.next_f64() < stay;
+            narrow(&mut cells, |&(arrow, _)| (arrow.head == here) == stays);
+        }
+
+        if let Some(&(arrow, ref variants)) = choose_weighted(rng, &cells, |&(arrow, _)| {
+            if arrow.variants.is_empty() {
+                arrow.settings.weight
+            } else {
+                let taken = history.as_ref().map_or(0, |history| {
+                    let head = &digraph.nodes[arrow.head].name;
+                    history.arrow_plays(&arrow.variants[0].path, &node.name, head)
+                });
+                arrow.settings.weight / (1.0 + taken as f64)
+            }
+        }) {
+            *dwell = if arrow.head == here {
+                *dwell + 1
+            } else {
+                0
+            };
+            let key = arrow_key(arrow);
+            *uses.entry(key.clone()).or_insert(0) += 1;
+            *state = arrow.head;
+            let variant = if arrow.settings.order == Order::Random || arrow.variants.is_empty() {
+                choose_weighted(rng, variants.as_slice(), |v| 1.0 / (1.0 + plays(history, v)))
+                    .cloned()
+            } else if arrow.settings.order == Order::Once {
+                // Take the next admitted variant in order. Once played
+                // through, the arrow is only taken when every exit is
+                // exhausted, and then repeats its last admitted variant.
+                let len = arrow.variants.len();
+                let position = positions.entry(key).or_insert(0);
+                let admitted = |i: &usize| {
+                    variants.iter().any(|v| ptr::eq(*v, &arrow.variants[*i]))
+                };
+                match (*position..len).find(&admitted) {
+                    Some(i) => {
+                        *position = i + 1;
+                        Some(&arrow.variants[i])
+                    }
+                    None => {
+                        *position = len;
+                        (0..len).rev().find(&admitted).map(|i| &arrow.variants[i])
+                    }
+                }
+            } else {
+                // Take the next admitted variant in order, starting over if
+                // the end has been reached.
+                let len = arrow.variants.len();
+                let position = positions.entry(key).or_insert(0);
+                let start = *position % len;
+                let found = (start..start + len).map(|i| i % len).find(|&i| {
+                    variants.iter().any(|v| ptr::eq(*v, &arrow.variants[i]))
+                });
+                found.map(|i| {
+                    *position = if i < start {
+                        len + i + 1
+                    } else {
+                        i + 1
+                    };
+                    &arrow.variants[i]
+                })
+            };
+            let crossfade = arrow.settings.crossfade.or(*crossfade);
+            variant.map(|variant| {
+                variant.step(&node.name, &digraph.nodes[arrow.head].name, crossfade)
+            })
         } else {
             None
         }
     }
 }
 
-impl<'a> Iterator for IntoRandomWalk {
-    type Item = path::PathBuf;
-    fn next(&mut self) -> Option<path::PathBuf> {
-        let path = self.next_once().map(|p| p.to_path_buf());
-        path.or_else(|| self.next_once().map(|p| p.to_path_buf()))
+impl Iterator for IntoRandomWalk {
+    type Item = Step;
+    fn next(&mut self) -> Option<Step> {
+        let replacement = self.steering.0.borrow_mut().digraph.take();
+        if let Some(digraph) = replacement {
+            self.state = self.digraph
+                             .nodes
+                             .get(self.state)
+                             .and_then(|node| digraph.index(&node.name))
+                             .unwrap_or(0);
+            self.digraph = digraph;
+        }
+        let relocation = self.steering.0.borrow_mut().relocation.take();
+        match relocation {
+            Some(Relocation::Reset) => {
+                self.state = 0;
+                self.dwell = 0;
+                self.entered = self.steering.elapsed();
+                self.uses.clear();
+                self.positions.clear();
+                self.steering.request(None);
+                if let Some(start) = self.digraph.nodes.get(0) {
+                    self.steering.enter(&start.name);
+                }
+            }
+            Some(Relocation::Jump(name)) => {
+                if let Some(index) = self.digraph.index(&name) {
+                    self.state = index;
+                    self.dwell = 0;
+                    self.entered = self.steering.elapsed();
+                    self.steering.enter(&name);
+                }
+            }
+            None => {}
+        }
+        if let Some(node) = self.digraph.nodes.get(self.state) {
+            self.steering.settle(&node.name);
+        }
+        let step = self.next_once().or_else(|| self.next_once());
+        {
+            let state = self.steering.0.borrow();
+            let level = if state.within(self.digraph.intensity) {
+                1.0
+            } else {
+                0.0
+            };
+            state.gain.set(level);
+        }
+        if let Some(ref step) = step {
+            if let Some(ref history) = self.history {
+                for path in step.paths.iter() {
+                    history.record(path);
+                }
+                if let Some(path) = step.paths.first() {
+                    history.record_arrow(path, &step.tail, &step.head);
+                }
+            }
+            if let Some((ref timeline, ref layer, offset, rate)) = self.timeline {
+                timeline.record(layer, offset + self.steering.elapsed(), step, rate);
+            }
+            self.steering.settle(&step.head);
+            if step.head != step.tail {
+                self.entered = self.steering.elapsed();
+                self.steering.enter(&step.head);
+            }
+        }
+        step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::path;
+
+    /// A generator whose every draw is the same.
+    struct Fixed(u32);
+
+    impl rand::Rng for Fixed {
+        fn next_u32(&mut self) -> u32 {
+            self.0
+        }
+    }
+
+    fn variant(tail: &str, head: &str, label: Option<&str>) -> Variant {
+        let name = match label {
+            Some(label) => format!("{}-{}-{}.ogg", tail, head, label),
+            None => format!("{}-{}.ogg", tail, head),
+        };
+        Variant::new(path::PathBuf::from(name), label)
+    }
+
+    fn arrow(builder: &mut DigraphBuilder, tail: &str, head: &str, labels: &[Option<&str>]) {
+        for label in labels {
+            builder.arrow(tail.to_string(), head.to_string(), variant(tail, head, *label));
+        }
+    }
+
+    /// The arrows, and the labels of the variants, of the first `count`
+    /// steps of a walk over the digraph of `builder`.
+    fn walk(builder: DigraphBuilder, count: usize) -> Vec<String> {
+        let digraph: Digraph = builder.into();
+        digraph.into_random_walk(Box::new(rand::XorShiftRng::new_unseeded()), Steering::new())
+               .take(count)
+               .map(|step| {
+                   match step.label {
+                       Some(label) => format!("{}-{}-{}", step.tail, step.head, label),
+                       None => format!("{}-{}", step.tail, step.head),
+                   }
+               })
+               .collect()
+    }
+
+    #[test]
+    fn span_parses_ranges_within_zero_and_one() {
+        assert_eq!("0.3-1".parse(),
+                   Ok(Span {
+                       low: 0.3,
+                       high: 1.0,
+                   }));
+        assert_eq!("0.5-0.5".parse::<Span>().map(|span| span.contains(0.5)), Ok(true));
+        assert_eq!("0.5-0.4".parse::<Span>(), Err(()));
+        assert_eq!("0-1.5".parse::<Span>(), Err(()));
+        assert_eq!("0.5".parse::<Span>(), Err(()));
+        assert_eq!("low-high".parse::<Span>(), Err(()));
+        let span: Span = "0.25-0.75".parse().unwrap();
+        assert_eq!(span.to_string().parse(), Ok(span));
+    }
+
+    #[test]
+    fn narrow_keeps_every_cell_rather_than_none() {
+        let mut cells = vec![1, 2, 3, 4];
+        narrow(&mut cells, |&cell| cell % 2 == 0);
+        assert_eq!(cells, vec![2, 4]);
+        narrow(&mut cells, |&cell| cell > 4);
+        assert_eq!(cells, vec![2, 4]);
+    }
+
+    #[test]
+    fn choose_weighted_skips_items_without_weight() {
+        let items = [0.0, 1.0, 2.0, 0.0];
+        assert_eq!(choose_weighted(&mut Fixed(0), &items, |&w| w), Some(&1.0));
+        assert_eq!(choose_weighted(&mut Fixed(!0), &items, |&w| w), Some(&2.0));
+        assert!(choose_weighted(&mut Fixed(0), &[0.0, 0.0], |&w| w).is_some());
+        assert_eq!(choose_weighted(&mut Fixed(0), &[] as &[f64], |&w| w), None);
+    }
+
+    #[test]
+    fn stay_decides_between_self_loop_and_exits() {
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "woods", &[None]);
+        arrow(&mut builder, "woods", "woods", &[None]);
+        arrow(&mut builder, "woods", "village", &[None]);
+        arrow(&mut builder, "village", "woods", &[None]);
+        builder.stay("woods", 1.0);
+        assert_eq!(walk(builder, 4),
+                   vec!["start-woods", "woods-woods", "woods-woods", "woods-woods"]);
+
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "woods", &[None]);
+        arrow(&mut builder, "woods", "woods", &[None]);
+        arrow(&mut builder, "woods", "village", &[None]);
+        arrow(&mut builder, "village", "woods", &[None]);
+        builder.stay("woods", 0.0);
+        assert_eq!(walk(builder, 4),
+                   vec!["start-woods", "woods-village", "village-woods", "woods-village"]);
+    }
+
+    #[test]
+    fn dwell_bounds_the_self_loops_in_a_row() {
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "woods", &[None]);
+        arrow(&mut builder, "woods", "woods", &[None]);
+        arrow(&mut builder, "woods", "village", &[None]);
+        arrow(&mut builder, "village", "woods", &[None]);
+        builder.min_dwell("woods", 2).max_dwell("woods", 2);
+        assert_eq!(walk(builder, 7),
+                   vec!["start-woods",
+                        "woods-woods",
+                        "woods-woods",
+                        "woods-village",
+                        "village-woods",
+                        "woods-woods",
+                        "woods-woods"]);
+    }
+
+    #[test]
+    fn after_dwell_locks_an_arrow_until_the_walk_has_been_in_its_tail() {
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "woods", &[None]);
+        arrow(&mut builder, "woods", "woods", &[None]);
+        arrow(&mut builder, "woods", "village", &[None]);
+        arrow(&mut builder, "village", "village", &[None]);
+        builder.stay("woods", 0.0).after_dwell("woods", "village", 2.0);
+        let digraph: Digraph = builder.into();
+        let clock = rc::Rc::new(cell::Cell::new(0));
+        let steering = Steering::new();
+        steering.set_clock(clock.clone(), 1.0);
+        let mut walk = digraph.into_random_walk(Box::new(rand::XorShiftRng::new_unseeded()),
+                                                steering);
+        let mut heads = vec![];
+        for seconds in 0..5 {
+            clock.set(seconds);
+            heads.push(walk.next().unwrap().head);
+        }
+        assert_eq!(heads, vec!["woods", "woods", "village", "village", "village"]);
+    }
+
+    #[test]
+    fn limit_exhausts_an_arrow_while_there_are_other_exits() {
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "woods", &[None]);
+        arrow(&mut builder, "woods", "woods", &[None]);
+        arrow(&mut builder, "woods", "village", &[None]);
+        arrow(&mut builder, "village", "woods", &[None]);
+        builder.stay("woods", 0.0).limit("woods", "village", 1);
+        assert_eq!(walk(builder, 5),
+                   vec!["start-woods", "woods-village", "village-woods", "woods-woods",
+                        "woods-woods"]);
+
+        // An exhausted arrow is taken again once every exit is.
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "woods", &[None]);
+        arrow(&mut builder, "woods", "woods", &[None]);
+        builder.limit("woods", "woods", 1);
+        assert_eq!(walk(builder, 3), vec!["start-woods", "woods-woods", "woods-woods"]);
+    }
+
+    #[test]
+    fn order_plays_the_variants_in_sequence() {
+        let labels = [Some("2"), Some("1"), Some("3")];
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "verse", &[None]);
+        arrow(&mut builder, "verse", "verse", &labels);
+        builder.order("verse", "verse", Order::Cycle);
+        assert_eq!(walk(builder, 6),
+                   vec!["start-verse", "verse-verse-1", "verse-verse-2", "verse-verse-3",
+                        "verse-verse-1", "verse-verse-2"]);
+
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "verse", &[None]);
+        arrow(&mut builder, "verse", "verse", &labels);
+        arrow(&mut builder, "verse", "outro", &[None]);
+        builder.order("verse", "verse", Order::Once).stay("verse", 1.0);
+        assert_eq!(walk(builder, 5),
+                   vec!["start-verse", "verse-verse-1", "verse-verse-2", "verse-verse-3",
+                        "verse-outro"]);
+
+        // Played through and without another exit, the last variant repeats.
+        let mut builder = DigraphBuilder::new();
+        arrow(&mut builder, "start", "verse", &[None]);
+        arrow(&mut builder, "verse", "verse", &labels[..2]);
+        builder.order("verse", "verse", Order::Once);
+        assert_eq!(walk(builder, 4),
+                   vec!["start-verse", "verse-verse-1", "verse-verse-2", "verse-verse-2"]);
+    }
+
+    #[test]
+    fn files_include_every_stem() {
+        let stem = |dir: &str| -> Digraph {
+            let mut builder = DigraphBuilder::new();
+            builder.arrow("start".to_string(),
+                          "woods".to_string(),
+                          Variant::new(path::Path::new(dir).join("start-woods.ogg"), None));
+            builder.into()
+        };
+        let digraph = stem("melody").stack(stem("bass")).unwrap();
+        assert_eq!(digraph.files(),
+                   vec![path::Path::new("melody/start-woods.ogg"),
+                        path::Path::new("bass/start-woods.ogg")]);
+    }
+
+    #[test]
+    fn variant_number_is_not_a_tag() {
+        let variant = Variant::new(path::PathBuf::from("verse-verse-1.ogg"), Some("1"));
+        assert_eq!(variant.number(), Some(1));
+        assert!(variant.tags().is_empty());
+
+        let variant = Variant::new(path::PathBuf::from("verse-verse-2-calm.ogg"), Some("2-calm"));
+        assert_eq!(variant.number(), Some(2));
+        assert_eq!(variant.tags().iter().collect::<Vec<_>>(), vec!["calm"]);
+
+        let variant = Variant::new(path::PathBuf::from("woods-woods-calm,dark.ogg"),
+                                   Some("calm,dark"));
+        assert_eq!(variant.number(), None);
+        assert_eq!(variant.tags().len(), 2);
     }
 }
@@ -0,0 +1,101 @@
+use regex;
+use std::path;
+use stream;
+
+pub const DEFAULT_PATTERN: &'static str = r"^(?P<tail>[^-]+)-(?P<head>[^-]+)(?:-(?P<variant>.+))?\.ogg$";
+
+const REQUIRED_CAPTURES: [&'static str; 2] = ["tail", "head"];
+
+/// Maps track file names to the arrows they belong to.
+///
+/// Node names matched by the default pattern are case insensitive and kept
+/// in lowercase. A custom pattern keeps them as written.
+#[derive(Clone)]
+pub struct Grammar {
+    re: regex::Regex,
+    fold_case: bool,
+}
+
+impl Grammar {
+    pub fn new(pattern: &str) -> stream::Result<Grammar> {
+        let re = try!(regex::Regex::new(pattern));
+        for name in REQUIRED_CAPTURES.iter() {
+            if !re.capture_names().any(|n| n == Some(name)) {
+                return Err(stream::Error::Capture(name.to_string()));
+            }
+        }
+        Ok(Grammar {
+            re: re,
+            fold_case: pattern == DEFAULT_PATTERN,
+        })
+    }
+
+    /// Spells a node name the way the names of matched file names are.
+    pub fn name(&self, name: &str) -> String {
+        if self.fold_case {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+
+    pub fn section(&self, path: &path::Path) -> Option<(String, String, Option<String>)> {
+        path.file_name()
+            .and_then(|os_str| os_str.to_str())
+            .and_then(|file_name| self.re.captures(file_name))
+            .and_then(|cap| {
+                match (cap.name("tail"), cap.name("head")) {
+                    (Some(tail), Some(head)) => {
+                        Some((self.name(tail),
+                              self.name(head),
+                              cap.name("variant").map(|s| s.to_string())))
+                    }
+                    _ => None,
+                }
+            })
+    }
+}
+
+impl Default for Grammar {
+    fn default() -> Grammar {
+        Grammar::new(DEFAULT_PATTERN).expect("valid default pattern")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path;
+
+    fn section(grammar: &Grammar, name: &str) -> Option<(String, String, Option<String>)> {
+        grammar.section(path::Path::new(name))
+    }
+
+    #[test]
+    fn new_requires_tail_and_head() {
+        assert!(Grammar::new(r"^(?P<tail>\w+)_(?P<head>\w+)\.ogg$").is_ok());
+        assert!(Grammar::new(r"^(?P<tail>\w+)\.ogg$").is_err());
+        assert!(Grammar::new(r"^(?P<tail>\w+)_(?P<head>\w+)(\.ogg$").is_err());
+    }
+
+    #[test]
+    fn default_pattern_folds_case() {
+        let grammar = Grammar::default();
+        assert_eq!(section(&grammar, "dir/Woods-Village.ogg"),
+                   Some(("woods".to_string(), "village".to_string(), None)));
+        assert_eq!(section(&grammar, "woods-village-2-Calm.ogg"),
+                   Some(("woods".to_string(), "village".to_string(), Some("2-Calm".to_string()))));
+        assert_eq!(section(&grammar, "woods.ogg"), None);
+        assert_eq!(section(&grammar, "woods-village.wav"), None);
+    }
+
+    #[test]
+    fn custom_pattern_keeps_case() {
+        let pattern = r"^(?P<tail>[^_]+)_(?P<head>[^_]+)(?:_(?P<variant>.+))?\.ogg$";
+        let grammar = Grammar::new(pattern).unwrap();
+        assert_eq!(grammar.name("Woods"), "Woods");
+        assert_eq!(section(&grammar, "Woods_Village_calm.ogg"),
+                   Some(("Woods".to_string(), "Village".to_string(), Some("calm".to_string()))));
+        assert_eq!(section(&grammar, "woods-village.ogg"), None);
+    }
+}
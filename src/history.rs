@@ -0,0 +1,348 @@
+use std::cell;
+use std::collections;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::path;
+use std::rc;
+use std::str::FromStr;
+use stream;
+use syntax;
+use syntax::parse_value;
+
+const HEADER: &'static str = "digraph123-history";
+const VERSION: u32 = 2;
+
+/// FNV-1a hash of the contents of a file.
+fn content_hash(path: &path::Path) -> io::Result<u64> {
+    let mut file = try!(fs::File::open(path));
+    let mut buf = [0; 8192];
+    let mut hash = 0xcbf29ce484222325u64;
+    loop {
+        let len = try!(file.read(&mut buf));
+        if len == 0 {
+            return Ok(hash);
+        }
+        for byte in &buf[..len] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+type Key = (path::PathBuf, u64);
+
+/// The canonical directory of the files of an arrow, and its tail and head.
+type ArrowKey = (path::PathBuf, String, String);
+
+/// The canonical path and content hash of a file, if it can be read.
+fn key(file: &path::Path) -> Option<Key> {
+    let canonical = fs::canonicalize(file).unwrap_or(file.to_path_buf());
+    content_hash(file).ok().map(|hash| (canonical, hash))
+}
+
+/// Files identified by their contents ahead of playing them, e.g. on
+/// another thread than the one playing.
+pub struct Identities(Vec<(path::PathBuf, Option<Key>)>);
+
+/// Identifies `files` by their canonical paths and contents.
+pub fn identify<I>(files: I) -> Identities
+    where I: IntoIterator,
+          I::Item: AsRef<path::Path>
+{
+    Identities(files.into_iter()
+                    .map(|file| {
+                        let file = file.as_ref();
+                        (file.to_path_buf(), key(file))
+                    })
+                    .collect())
+}
+
+struct HistoryState {
+    path: path::PathBuf,
+    plays: collections::BTreeMap<Key, u64>,
+    arrows: collections::BTreeMap<ArrowKey, u64>,
+    keys: collections::HashMap<path::PathBuf, Option<Key>>,
+    changed: bool,
+}
+
+impl HistoryState {
+    /// The canonical path and content hash of a file, as last identified.
+    fn key(&mut self, file: &path::Path) -> Option<Key> {
+        self.keys.entry(file.to_path_buf()).or_insert_with(|| key(file)).clone()
+    }
+
+    /// The arrow from `tail` to `head` among the files in the directory of
+    /// `file`, as last identified.
+    fn arrow_key(&mut self, file: &path::Path, tail: &str, head: &str) -> Option<ArrowKey> {
+        self.key(file).map(|(canonical, _)| {
+            let dir = canonical.parent().map_or(path::PathBuf::new(), path::Path::to_path_buf);
+            (dir, tail.to_string(), head.to_string())
+        })
+    }
+
+    fn read<R: BufRead>(&mut self, reader: R) -> stream::Result<()> {
+        for (i, line) in reader.lines().enumerate() {
+            let line = try!(line);
+            let number = i + 1;
+            if number == 1 {
+                let fields: Vec<&str> = line.splitn(2, '\t').collect();
+                let version: Option<u32> = fields.get(1).and_then(|v| u32::from_str(v).ok());
+                if fields[0] != HEADER || version.map_or(true, |v| v < 1 || v > VERSION) {
+                    return Err(syntax::error(number, "not a history file".to_string()));
+                }
+                continue;
+            }
+            // The path comes last as it may contain tabs.
+            let fields: Vec<&str> = if line.starts_with("arrow\t") {
+                line.splitn(5, '\t').collect()
+            } else {
+                line.splitn(4, '\t').collect()
+            };
+            match (fields[0], fields.len()) {
+                ("file", 4) => {
+                    let hash = try!(u64::from_str_radix(fields[1], 16).map_err(|_| {
+                        syntax::error(number, format!("invalid hash '{}'", fields[1]))
+                    }));
+                    let count = try!(parse_value(number, "count", fields[2]));
+                    self.plays.insert((path::PathBuf::from(fields[3]), hash), count);
+                }
+                ("arrow", 5) => {
+                    let count = try!(parse_value(number, "count", fields[1]));
+                    let key = (path::PathBuf::from(fields[4]),
+                               fields[2].to_string(),
+                               fields[3].to_string());
+                    self.arrows.insert(key, count);
+                }
+                _ => return Err(syntax::error(number, format!("unknown entry '{}'", line))),
+            }
+        }
+        Ok(())
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(writeln!(writer, "{}\t{}", HEADER, VERSION));
+        for (&(ref file, hash), count) in self.plays.iter() {
+            try!(writeln!(writer, "file\t{:016x}\t{}\t{}", hash, count, file.display()));
+        }
+        for (&(ref dir, ref tail, ref head), count) in self.arrows.iter() {
+            try!(writeln!(writer, "arrow\t{}\t{}\t{}\t{}", count, tail, head, dir.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Counts of how many times each file has been played and each arrow taken,
+/// kept across sessions in a state file.
+///
+/// Files are identified by their canonical path and a hash of their
+/// contents, so a file that is replaced starts over at no plays. Arrows are
+/// identified by the canonical directory of their files and the names of
+/// their tail and head.
+#[derive(Clone)]
+pub struct History(rc::Rc<cell::RefCell<HistoryState>>);
+
+impl History {
+    /// Loads the history stored at `path`, or starts an empty one if there is
+    /// no such file.
+    pub fn load<P: AsRef<path::Path>>(path: P) -> stream::Result<History> {
+        let path = path.as_ref();
+        let mut state = HistoryState {
+            path: path.to_path_buf(),
+            plays: collections::BTreeMap::new(),
+            arrows: collections::BTreeMap::new(),
+            keys: collections::HashMap::new(),
+            changed: false,
+        };
+        if path.is_file() {
+            let file = try!(fs::File::open(path));
+            try!(state.read(io::BufReader::new(file))
+                      .map_err(|err| stream::Error::File(path.to_path_buf(), Box::new(err))));
+        }
+        Ok(History(rc::Rc::new(cell::RefCell::new(state))))
+    }
+
+    /// Writes the history back to its state file if anything was played
+    /// since it was loaded or last saved.
+    pub fn save(&self) -> stream::Result<()> {
+        let mut state = self.0.borrow_mut();
+        if !state.changed {
+            return Ok(());
+        }
+        let mut temporary = state.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let temporary = path::PathBuf::from(temporary);
+        let res = fs::File::create(&temporary).and_then(|file| {
+            let mut writer = io::BufWriter::new(file);
+            try!(state.write(&mut writer));
+            writer.flush()
+        });
+        try!(res.and_then(|_| fs::rename(&temporary, &state.path)).map_err(|err| {
+            stream::Error::File(state.path.clone(), Box::new(stream::Error::Io(err)))
+        }));
+        state.changed = false;
+        Ok(())
+    }
+
+    /// Takes over the identities of files, replacing those the history had
+    /// for them, e.g. after their directory was rescanned.
+    ///
+    /// Files are otherwise identified once, the first time they are looked
+    /// up.
+    pub fn learn(&self, identities: Identities) {
+        self.0.borrow_mut().keys.extend(identities.0);
+    }
+
+    /// Number of times `file` has been played.
+    pub fn plays(&self, file: &path::Path) -> u64 {
+        let mut state = self.0.borrow_mut();
+        match state.key(file) {
+            Some(key) => state.plays.get(&key).cloned().unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /// Number of times the arrow from `tail` to `head` has been taken among
+    /// the files in the directory of `file`.
+    pub fn arrow_plays(&self, file: &path::Path, tail: &str, head: &str) -> u64 {
+        let mut state = self.0.borrow_mut();
+        match state.arrow_key(file, tail, head) {
+            Some(key) => state.arrows.get(&key).cloned().unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /// Records that the arrow from `tail` to `head` among the files in the
+    /// directory of `file` was taken.
+    pub fn record_arrow(&self, file: &path::Path, tail: &str, head: &str) {
+        let mut state = self.0.borrow_mut();
+        if let Some(key) = state.arrow_key(file, tail, head) {
+            *state.arrows.entry(key).or_insert(0) += 1;
+            state.changed = true;
+        }
+    }
+
+    pub fn record(&self, file: &path::Path) {
+        let mut state = self.0.borrow_mut();
+        if let Some(key) = state.key(file) {
+            // Forget earlier contents of the file.
+            let stale: Vec<Key> = state.plays
+                                       .keys()
+                                       .filter(|&&(ref path, hash)| path == &key.0 && hash != key.1)
+                                       .cloned()
+                                       .collect();
+            for stale in stale {
+                state.plays.remove(&stale);
+            }
+            *state.plays.entry(key).or_insert(0) += 1;
+            state.changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_path(name: &str) -> path::PathBuf {
+        env::temp_dir().join(format!("digraph123-history-{}", name))
+    }
+
+    fn write_file(path: &path::Path, contents: &[u8]) {
+        fs::File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    fn empty_state() -> HistoryState {
+        HistoryState {
+            path: path::PathBuf::new(),
+            plays: collections::BTreeMap::new(),
+            arrows: collections::BTreeMap::new(),
+            keys: collections::HashMap::new(),
+            changed: false,
+        }
+    }
+
+    #[test]
+    fn read_then_write_round_trips() {
+        let text = "digraph123-history\t2\n\
+                    file\t00000000000000ff\t3\t/music/a b.ogg\n\
+                    file\t0123456789abcdef\t1\t/music/c.ogg\n\
+                    arrow\t2\twoods\tvillage\t/music/tab\tdir\n";
+        let mut state = empty_state();
+        state.read(io::Cursor::new(text.as_bytes())).unwrap();
+        assert_eq!(state.plays.get(&(path::PathBuf::from("/music/a b.ogg"), 0xff)),
+                   Some(&3));
+        let key = (path::PathBuf::from("/music/tab\tdir"),
+                   "woods".to_string(),
+                   "village".to_string());
+        assert_eq!(state.arrows.get(&key), Some(&2));
+        let mut bytes = vec![];
+        state.write(&mut bytes).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), text);
+    }
+
+    #[test]
+    fn read_accepts_version_1_and_rejects_unknown_entries() {
+        let mut state = empty_state();
+        let text = "digraph123-history\t1\nfile\t00000000000000ff\t3\t/music/a.ogg\n";
+        assert!(state.read(io::Cursor::new(text.as_bytes())).is_ok());
+        for text in &["digraph123-history\t3\n",
+                      "digraph123-history\t1\nfile\txyz\t1\t/a.ogg\n",
+                      "digraph123-history\t2\narrow\t1\twoods\n",
+                      "digraph123-history\t2\nplay\t00\t1\t/a.ogg\n"] {
+            assert!(state.read(io::Cursor::new(text.as_bytes())).is_err());
+        }
+    }
+    #[test]
+    fn plays_follow_the_contents_of_a_file() {
+        let track = temp_path("track.ogg");
+        let state = temp_path("state");
+        fs::remove_file(&state).ok();
+        write_file(&track, b"first");
+
+        let history = History::load(&state).unwrap();
+        history.record(&track);
+        history.record(&track);
+        assert_eq!(history.plays(&track), 2);
+        history.save().unwrap();
+        assert_eq!(History::load(&state).unwrap().plays(&track), 2);
+
+        // A replaced file starts over once the history learns of it.
+        write_file(&track, b"second");
+        assert_eq!(history.plays(&track), 2);
+        history.learn(identify(vec![&track]));
+        assert_eq!(history.plays(&track), 0);
+        history.record(&track);
+        history.save().unwrap();
+        let reloaded = History::load(&state).unwrap();
+        let plays = reloaded.plays(&track);
+        fs::remove_file(&track).ok();
+        fs::remove_file(&state).ok();
+        assert_eq!(plays, 1);
+        assert_eq!(reloaded.0.borrow().plays.len(), 1);
+    }
+
+    #[test]
+    fn arrows_are_counted_per_directory() {
+        let track = temp_path("arrow.ogg");
+        let state = temp_path("arrow-state");
+        fs::remove_file(&state).ok();
+        write_file(&track, b"track");
+
+        let history = History::load(&state).unwrap();
+        history.record_arrow(&track, "woods", "village");
+        history.save().unwrap();
+        let reloaded = History::load(&state).unwrap();
+        let plays = (reloaded.arrow_plays(&track, "woods", "village"),
+                     reloaded.arrow_plays(&track, "village", "woods"),
+                     reloaded.arrow_plays(path::Path::new("/elsewhere/arrow.ogg"),
+                                          "woods",
+                                          "village"));
+        fs::remove_file(&track).ok();
+        fs::remove_file(&state).ok();
+        assert_eq!(plays, (1, 0, 0));
+    }
+}
@@ -0,0 +1,117 @@
+use digraph123::{ogg, Digraph, Role, VoiceConfig};
+use std::path;
+
+pub fn format_duration(seconds: f64) -> String {
+    let minutes = (seconds / 60.0).floor();
+    let seconds = seconds - minutes * 60.0;
+    if minutes >= 60.0 {
+        format!("{}:{:02}:{:06.3}",
+                (minutes / 60.0).floor(),
+                minutes % 60.0,
+                seconds)
+    } else {
+        format!("{}:{:06.3}", minutes, seconds)
+    }
+}
+
+fn file_name(path: &path::Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// Prints the nodes, arrows and files of a digraph along with per-file
+/// formats and comments, and returns the number of files that could not be
+/// read.
+pub fn print(dir: &str, voice_config: VoiceConfig, digraph: &Digraph) -> usize {
+    println!("{} ({} channels, {} Hz)", dir, voice_config.0, voice_config.1);
+    if let Some(span) = digraph.intensity() {
+        println!("  layer intensity={}", span);
+    }
+    if let Some(bpm) = digraph.tempo() {
+        println!("  layer tempo={}", bpm);
+    }
+    for (tag, span) in digraph.tag_intensities() {
+        println!("  tag {} intensity={}", tag, span);
+    }
+
+    let nodes = digraph.nodes();
+    let mut arrows = 0;
+    let mut files = 0;
+    let mut failures = 0;
+    let mut seconds = 0.0;
+    for node in nodes {
+        let mut settings = vec![];
+        if let Some(stay) = node.stay() {
+            settings.push(format!("stay={}", stay));
+        }
+        if let Some(min) = node.min_dwell() {
+            settings.push(format!("min={}", min));
+        }
+        if let Some(max) = node.max_dwell() {
+            settings.push(format!("max={}", max));
+        }
+        println!("  node {} {}", node.name(), settings.join(" "));
+
+        for arrow in node.arrows() {
+            if arrow.variants().is_empty() {
+                println!("    -> {} (silent)", nodes[arrow.head()].name());
+                continue;
+            }
+            arrows += 1;
+            let mut settings = vec![format!("weight={}", arrow.weight()),
+                                    format!("order={}", arrow.order())];
+            if arrow.role() != Role::Ambient {
+                settings.push(format!("role={}", arrow.role()));
+            }
+            if let Some(limit) = arrow.limit() {
+                settings.push(format!("limit={}", limit));
+            }
+            if let Some(span) = arrow.intensity() {
+                settings.push(format!("intensity={}", span));
+            }
+            if let Some(seconds) = arrow.after() {
+                settings.push(format!("after={}", seconds));
+            }
+            if let Some(seconds) = arrow.after_dwell() {
+                settings.push(format!("dwell={}", seconds));
+            }
+            if let Some(seconds) = arrow.crossfade() {
+                settings.push(format!("crossfade={}", seconds));
+            }
+            println!("    -> {} {}", nodes[arrow.head()].name(), settings.join(" "));
+
+            for variant in arrow.variants() {
+                files += 1;
+                println!("      {} [{}]", file_name(variant.path()), variant.label().unwrap_or(""));
+                match ogg::info(variant.path()) {
+                    Ok(info) => {
+                        seconds += info.seconds();
+                        let splice_point = info.comments.get("SPLICEPOINT");
+                        println!("        {} channels, {} Hz, {} ({} frames), splice point {}",
+                                 info.channels,
+                                 info.rate,
+                                 format_duration(info.seconds()),
+                                 info.frames,
+                                 splice_point.first().cloned().unwrap_or("none"));
+                        for &(ref key, ref value) in info.comments.comments.iter() {
+                            if !key.eq_ignore_ascii_case("SPLICEPOINT") {
+                                println!("        {}={}", key, value);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        failures += 1;
+                        println!("        error: {}", err);
+                    }
+                }
+            }
+        }
+    }
+    println!("  total: {} nodes, {} arrows, {} files, {}",
+             nodes.len(),
+             arrows,
+             files,
+             format_duration(seconds));
+    failures
+}
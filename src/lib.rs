@@ -0,0 +1,32 @@
+//! Play digraph shaped audio recordings using random walk.
+//!
+//! A `MixerBuilder` scans digraph directories into one `Player` per
+//! directory and mixes them into a single `Mixer`. Both are `Stream`s of
+//! interleaved samples. Each player draws its tracks from a random walk over
+//! a `Digraph`, which can be steered while playing through its `Steering`.
+
+extern crate rand;
+extern crate regex;
+extern crate vorbis;
+
+pub mod analysis;
+pub mod builder;
+pub mod compiled;
+pub mod digraph;
+pub mod grammar;
+pub mod history;
+pub mod manifest;
+pub mod ogg;
+pub mod stream;
+mod syntax;
+pub mod timeline;
+pub mod watch;
+
+pub use builder::{Layer, MixerBuilder, PlayerBuilder, PlayerOptions, Quantize, VoiceConfig};
+pub use digraph::{Digraph, DigraphBuilder, IntoRandomWalk, Order, Role, Span, Step, Steering,
+                  Tags, Variant};
+pub use grammar::Grammar;
+pub use history::History;
+pub use stream::{Control, Crossfade, Cue, Cued, Delayed, Error, FadeOut, Fader, Mixer, Player,
+                 Result, Stream, Track, Window};
+pub use timeline::Timeline;
@@ -1,26 +1,21 @@
 extern crate clap;
 extern crate cpal;
-extern crate rand;
-extern crate regex;
-extern crate vorbis;
+extern crate digraph123;
 
-#[macro_use]
-extern crate lazy_static;
+mod command;
+mod info;
 
-mod digraph;
-mod stream;
-
-use std::fs;
+use digraph123::{analysis, builder, compiled, digraph, grammar, ogg, stream, timeline, watch};
+use digraph123::{History, Mixer, MixerBuilder, PlayerBuilder, Stream, Timeline, VoiceConfig};
+use std::cmp;
 use std::io;
 use std::io::Write;
 use std::ops::DerefMut;
 use std::path;
 use std::process;
+use std::str::FromStr;
 use std::thread;
 use std::time;
-use stream::Stream;
-
-type VoiceConfig = (u8, u32);
 
 macro_rules! print_error {
     ($err:expr, $fmt:tt $(, $arg:expr)*) => {{
@@ -45,136 +40,501 @@ macro_rules! insist {
     }   
 }
 
-struct PlayerBuilder {
-    digraph_builder: digraph::DigraphBuilder,
-    voice_config: Option<VoiceConfig>,
+fn create_voice(voice_config: VoiceConfig, endpoint: cpal::Endpoint) -> cpal::Voice {
+    let format = {
+        let formats = endpoint.get_supported_formats_list();
+        let formats = insist!(formats,
+                              "fatal: failed to get list of supported audio formats");
+
+        formats.filter(|f| f.samples_rate.0 as u32 == voice_config.1)
+               .filter(|f| f.channels.len() == voice_config.0 as usize)
+               .filter(|f| f.data_type == cpal::SampleFormat::F32)
+               .next()
+    };
+    let format = if let Some(format) = format {
+        format
+    } else {
+        panic!("voice format not supported");
+    };
+
+    cpal::Voice::new(&endpoint, &format).expect("Failed to create a voice")
+}
+
+fn pattern_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("pattern")
+        .help("A regular expression matching track file names, with named captures for tail, \
+               head and optionally variant. Node names are case insensitive with the \
+               default pattern and kept as written otherwise")
+        .long("pattern")
+        .short("p")
+        .takes_value(true)
+        .default_value(grammar::DEFAULT_PATTERN)
 }
 
-impl PlayerBuilder {
-    fn new() -> PlayerBuilder {
-        PlayerBuilder {
-            digraph_builder: digraph::DigraphBuilder::new(),
-            voice_config: None,
+/// Splits a coupling rule of the form "DIR1:NODE1=DIR2:NODE2".
+fn parse_coupling(rule: &str) -> Option<(&str, &str, &str, &str)> {
+    fn layer_node(s: &str) -> Option<(&str, &str)> {
+        let mut parts = s.rsplitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(node), Some(layer)) => Some((layer, node)),
+            _ => None,
+        }
+    }
+
+    let mut sides = rule.splitn(2, '=');
+    match (sides.next().and_then(layer_node), sides.next().and_then(layer_node)) {
+        (Some((source, node)), Some((target, target_node))) => {
+            Some((source, node, target, target_node))
         }
+        _ => None,
     }
+}
 
-    fn path_to_voice_config(path: &path::Path) -> Result<VoiceConfig, stream::Error> {
-        let file = try!(fs::File::open(path));
-        let mut decoder = try!(vorbis::Decoder::new(file));
-        let packet = try!(decoder.packets().next().expect("first packet"));
-        Ok((packet.channels as u8, packet.rate as u32))
+/// Splits a layer of the form "DIRS[*WALKERS[+OFFSET]]".
+fn parse_layer(layer: &str) -> Option<(Vec<&str>, usize, f64)> {
+    let mut parts = layer.splitn(2, '*');
+    let dirs: Vec<&str> = parts.next().unwrap_or("").split(',').collect();
+    match parts.next() {
+        Some(canon) => {
+            let mut canon = canon.splitn(2, '+');
+            let walkers = canon.next().and_then(|walkers| usize::from_str(walkers).ok());
+            let offset = canon.next().map_or(Some(0.0), |offset| f64::from_str(offset).ok());
+            match (walkers, offset) {
+                (Some(walkers), Some(offset)) if walkers > 0 && offset >= 0.0 => {
+                    Some((dirs, walkers, offset))
+                }
+                _ => None,
+            }
+        }
+        None => Some((dirs, 1, 0.0)),
     }
+}
+
+fn grammar(matches: &clap::ArgMatches) -> grammar::Grammar {
+    let pattern = matches.value_of("pattern").unwrap_or(grammar::DEFAULT_PATTERN);
+    insist!(grammar::Grammar::new(pattern),
+            "fatal: invalid file name pattern '{}'",
+            pattern)
+}
 
-    fn path_to_section(path: &path::Path) -> Option<(String, String, Option<String>)> {
-        lazy_static! {
-            static ref SECTION_RE: regex::Regex = regex::Regex::new(r"^([^-]+)-([^-]+)(?:-(.+))?.ogg$").unwrap();
+fn compile(matches: &clap::ArgMatches) {
+    let grammar = grammar(matches);
+    let mut failed = false;
+    for dir in matches.values_of("dir").map(|v| v.collect()).unwrap_or(vec![]) {
+        let mut player_builder = PlayerBuilder::new(&grammar);
+        let res = player_builder.scan(dir).map(|_| ());
+        for warning in player_builder.warnings() {
+            print_error!(warning, "warning: ignoring file");
+        }
+        let res = res.and_then(|_| {
+            if let Some((voice_config, digraph)) = player_builder.into_digraph() {
+                compiled::save(path::Path::new(dir), voice_config, &digraph)
+            } else {
+                Err(digraph123::Error::NoItems)
+            }
+        });
+        if let Err(err) = res {
+            let err = digraph123::Error::Dir(dir.to_string(), Box::new(err));
+            print_error!(&err, "error: failed to compile directory");
+            failed = true;
         }
-        path.file_name()
-            .and_then(|os_str| os_str.to_str())
-            .and_then(|file_name| SECTION_RE.captures(file_name))
-            .map(|cap| {
-                (cap[1].to_lowercase().to_string(),
-                 cap[2].to_lowercase().to_string(),
-                 cap.at(3).map(|s| s.to_string()))
-            })
     }
+    if failed {
+        process::exit(1);
+    }
+}
 
-    fn path(&mut self, path: path::PathBuf) -> stream::Result<&mut Self> {
-        if let Some((tail, head, _)) = Self::path_to_section(&path) {
-            match Self::path_to_voice_config(&path) {
-                Ok(file_voice_config) => {
-                    self.voice_config = self.voice_config.or(Some(file_voice_config));
-                    if Some(file_voice_config) != self.voice_config {
-                        return Err(stream::Error::AudioFormat);
-                    }
-                    self.digraph_builder.arrow(tail, head, path);
-                    Ok(self)
+/// Loads the digraphs of stem directories and stacks them, printing the
+/// files skipped.
+fn load_dirs(grammar: &grammar::Grammar,
+             dirs: &[String])
+             -> stream::Result<(VoiceConfig, digraph::Digraph)> {
+    let mut warnings = vec![];
+    let res = builder::stems(grammar, dirs, &mut warnings);
+    for warning in warnings.iter() {
+        print_error!(warning, "warning: ignoring file");
+    }
+    res
+}
+
+fn info(matches: &clap::ArgMatches) {
+    let grammar = grammar(matches);
+    let mut failed = false;
+    for dir in matches.values_of("dir").map(|v| v.collect()).unwrap_or(vec![]) {
+        match load_dirs(&grammar, &[dir.to_string()]) {
+            Ok((voice_config, digraph)) => {
+                if info::print(dir, voice_config, &digraph) > 0 {
+                    failed = true;
                 }
-                Err(err) => Err(stream::Error::File(path, Box::new(err))),
             }
-        } else {
-            Ok(self)
+            Err(err) => {
+                print_error!(&err, "error: failed to read directory");
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// Parses a non-negative number of seconds given for `name`.
+fn seconds_arg(matches: &clap::ArgMatches, name: &str) -> f64 {
+    let value = matches.value_of(name).unwrap_or("0");
+    match f64::from_str(value) {
+        Ok(seconds) if seconds >= 0.0 => seconds,
+        _ => {
+            writeln!(&mut io::stderr(), "fatal: invalid {} '{}'", name, value).ok();
+            process::exit(1);
         }
     }
+}
+
+fn describe_step(step: &digraph::Step) -> String {
+    let files: Vec<String> = step.paths
+                                 .iter()
+                                 .filter_map(|path| path.file_name())
+                                 .map(|name| name.to_string_lossy().into_owned())
+                                 .collect();
+    format!("{} -> {} ({})", step.tail, step.head, files.join(", "))
+}
 
-    fn build(self) -> stream::Result<Option<(VoiceConfig, stream::Player)>> {
-        if let Some(voice_config) = self.voice_config {
-            let digraph: digraph::Digraph = self.digraph_builder.into();
-            let tracks = digraph.into_random_walk(Box::new(rand::thread_rng()))
-                                .map(|p| stream::Track::vorbis(p.as_path()));
-            Ok(Some((voice_config, try!(stream::Player::new(Box::new(tracks))))))
+/// Plays `samples` samples of `mixer` followed by `silence` samples of
+/// silence.
+fn audition(voice: &mut cpal::Voice, mixer: &mut Mixer, samples: usize, silence: usize) {
+    let mut played = 0;
+    let mut ended = false;
+    while played < samples + silence {
+        if played >= samples {
+            ended = true;
+        }
+        let max_read = if ended {
+            samples + silence - played
         } else {
-            Ok(None)
+            cmp::min(mixer.max_read(), samples - played)
+        };
+        if max_read == 0 {
+            if let Err(err) = mixer.load() {
+                print_error!(&err, "warning: an error occurred loading the mixer");
+            }
+            ended = mixer.max_read() == 0;
+            continue;
         }
+        match voice.append_data(max_read) {
+            cpal::UnknownTypeBuffer::F32(mut buffer) => {
+                for out in buffer.deref_mut().iter_mut() {
+                    *out = 0.0;
+                }
+                if !ended {
+                    mixer.read_add(buffer.deref_mut());
+                }
+                played += buffer.deref_mut().len();
+            }
+            _ => panic!("unsupported buffer type"),
+        };
+        voice.play();
     }
 }
 
-struct MixerBuilder {
-    streams: Vec<Box<stream::Stream>>,
-    voice_config: Option<VoiceConfig>,
+fn seams(matches: &clap::ArgMatches) {
+    let grammar = grammar(matches);
+    let before = seconds_arg(matches, "before");
+    let after = seconds_arg(matches, "after");
+    for dir in matches.values_of("dir").map(|v| v.collect()).unwrap_or(vec![]) {
+        let (voice_config, digraph) = insist!(load_dirs(&grammar, &[dir.to_string()]),
+                                              "fatal: failed to read directory");
+        let samples = |seconds: f64| {
+            (seconds * voice_config.1 as f64).round() as usize * voice_config.0 as usize
+        };
+        let endpoint = cpal::get_default_endpoint().expect("default endpoint");
+        let mut voice = create_voice(voice_config, endpoint);
+        let seams = digraph.seams(matches.is_present("variants"));
+        // The seams of a step with each that may follow it come in a row.
+        let mut window: Option<(&[path::PathBuf], stream::Window)> = None;
+        for (i, &(ref first, ref second)) in seams.iter().enumerate() {
+            println!("{} seam {}/{}", dir, i + 1, seams.len());
+            println!("  {}", describe_step(first));
+            println!("  {}", describe_step(second));
+            if window.as_ref().map_or(true, |&(paths, _)| paths != &first.paths[..]) {
+                window = match builder::seam_window(first, samples(before), samples(after)) {
+                    Ok(tail) => Some((&first.paths, tail)),
+                    Err(err) => {
+                        print_error!(&err, "warning: skipping seam");
+                        continue;
+                    }
+                };
+            }
+            if let Some((_, ref tail)) = window {
+                match builder::seam(tail, second) {
+                    Ok((player, _)) => {
+                        let mut mixer = Mixer::new(vec![Box::new(player)]);
+                        audition(&mut voice, &mut mixer, samples(before + after), samples(0.5));
+                    }
+                    Err(err) => print_error!(&err, "warning: skipping seam"),
+                }
+            }
+        }
+        while voice.get_pending_samples() > 0 {
+            thread::sleep(time::Duration::from_millis(100));
+        }
+    }
 }
 
-impl MixerBuilder {
-    fn new() -> MixerBuilder {
-        MixerBuilder {
-            streams: vec![],
-            voice_config: None,
+fn analyze(matches: &clap::ArgMatches) {
+    let grammar = grammar(matches);
+    let window = seconds_arg(matches, "window");
+    let mut failed = false;
+    for dir in matches.values_of("dir").map(|v| v.collect()).unwrap_or(vec![]) {
+        let (voice_config, digraph) = match load_dirs(&grammar, &[dir.to_string()]) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                print_error!(&err, "error: failed to read directory");
+                failed = true;
+                continue;
+            }
+        };
+        let frames = (window * voice_config.1 as f64).round() as usize;
+        let (seams, errors) = analysis::analyze_all(&digraph,
+                                                    voice_config,
+                                                    frames,
+                                                    matches.is_present("variants"));
+        for (first, second, err) in errors {
+            print_error!(&err,
+                         "error: failed to decode seam {} | {}",
+                         describe_step(&first),
+                         describe_step(&second));
+            failed = true;
         }
+        let total = seams.len();
+        let shown: Vec<_> = seams.into_iter()
+                                 .filter(|seam| matches.is_present("all") || seam.is_suspect())
+                                 .collect();
+        println!("{}: {} of {} seams suspect",
+                 dir,
+                 shown.iter().filter(|seam| seam.is_suspect()).count(),
+                 total);
+        for seam in shown {
+            println!("  {:6.2}  jump {:.4}  level {:+.1} dB  dc {:.4}",
+                     seam.severity(),
+                     seam.discontinuity,
+                     seam.level_jump,
+                     seam.dc_offset);
+            println!("          {}", describe_step(&seam.first));
+            println!("          {}", describe_step(&seam.second));
+        }
+    }
+    if failed {
+        process::exit(1);
     }
+}
 
-    fn dir(&mut self, dir: &str) -> stream::Result<&mut Self> {
-        fn inner(this: &mut MixerBuilder, dir: &str) -> stream::Result<()> {
-            let mut player_builder = PlayerBuilder::new();
-            for entry in try!(fs::read_dir(dir)) {
-                let entry = try!(entry);
-                if let Err(err) = player_builder.path(entry.path()) {
-                    print_error!(&err, "warning: ignoring file");
+fn print_suggestion(path: &path::Path, suggestion: &analysis::Suggestion) {
+    let length = suggestion.frames * suggestion.channels as u64;
+    let end = suggestion.musical_end * suggestion.channels as u64;
+    println!("{}", path.display());
+    println!("  music ends at {} of {}, tail {}",
+             info::format_duration(suggestion.seconds(end)),
+             info::format_duration(suggestion.seconds(length)),
+             info::format_duration(suggestion.seconds(length.saturating_sub(end))));
+    match suggestion.splice_point {
+        Some(splice_point) => println!("  suggested SPLICEPOINT={}", splice_point),
+        None => println!("  no tail to splice over"),
+    }
+    if !suggestion.current.is_empty() {
+        println!("  current SPLICEPOINT={}", suggestion.current.join(", "));
+    }
+}
+
+fn splice(matches: &clap::ArgMatches) {
+    let grammar = grammar(matches);
+    let comments = matches.is_present("comment");
+    let mut failed = false;
+    for dir in matches.values_of("dir").map(|v| v.collect()).unwrap_or(vec![]) {
+        let dirs: Vec<String> = dir.split(',').map(str::to_string).collect();
+        let digraph = match load_dirs(&grammar, &dirs) {
+            Ok((_, digraph)) => digraph,
+            Err(err) => {
+                print_error!(&err, "error: failed to read directory");
+                failed = true;
+                continue;
+            }
+        };
+        let variants = digraph.nodes()
+                              .iter()
+                              .flat_map(|node| node.arrows())
+                              .flat_map(|arrow| arrow.variants());
+        for variant in variants {
+            // The stems of a variant give way to the next at the earliest of
+            // their splice points.
+            let mut stacked: Option<u64> = None;
+            let stems = variant.stems().iter().map(|path| path.as_path());
+            for path in Some(variant.path()).into_iter().chain(stems) {
+                let suggestion = match analysis::suggest_splice_point(path, digraph.tempo()) {
+                    Ok(suggestion) => suggestion,
+                    Err(err) => {
+                        let err = digraph123::Error::File(path.to_path_buf(), Box::new(err));
+                        print_error!(&err, "error: failed to analyze file");
+                        failed = true;
+                        continue;
+                    }
+                };
+                if let Some(splice_point) = suggestion.splice_point {
+                    stacked = Some(stacked.map_or(splice_point, |sp| cmp::min(sp, splice_point)));
+                }
+                if comments {
+                    if let Some(splice_point) = suggestion.splice_point {
+                        println!("{}\tSPLICEPOINT={}", path.display(), splice_point);
+                    }
+                    continue;
                 }
+                print_suggestion(path, &suggestion);
             }
-            if let Some((voice_config, player)) = try!(player_builder.build()) {
-                this.voice_config = this.voice_config.or(Some(voice_config));
-                if Some(voice_config) != this.voice_config {
-                    return Err(stream::Error::AudioFormat);
+            if !comments && !variant.stems().is_empty() {
+                match stacked {
+                    Some(splice_point) => {
+                        println!("  stems splice together at SPLICEPOINT={}", splice_point)
+                    }
+                    None => println!("  no stem has a tail to splice over"),
                 }
-                this.streams.push(Box::new(player));
-                Ok(())
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// A change to the comments of a file.
+enum Edit<'a> {
+    Remove(&'a str),
+    Set(&'a str, &'a str),
+    Add(&'a str, &'a str),
+}
+
+/// Comments read as numbers when playing.
+const NUMERIC_COMMENTS: [&'static str; 4] = ["SPLICEPOINT", "CROSSFADE", "MINDWELL", "MAXDWELL"];
+
+/// Splits a comment of the form "KEY=VALUE", checking that the key is a
+/// valid field name and that a splice point, crossfade or dwell count is a
+/// number.
+fn parse_comment(comment: &str) -> Option<(&str, &str)> {
+    let mut parts = comment.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if valid_key(key) => {
+            let numeric = NUMERIC_COMMENTS.iter().any(|name| key.eq_ignore_ascii_case(name));
+            if numeric && u64::from_str(value).is_err() {
+                None
             } else {
-                Err(stream::Error::NoItems)
+                Some((key, value))
             }
         }
-        inner(self, dir)
-            .map_err(|err| stream::Error::Dir(dir.to_string(), Box::new(err)))
-            .and(Ok(self))
+        _ => None,
     }
+}
 
-    fn build(self) -> stream::Result<(VoiceConfig, f32, stream::Mixer)> {
-        if let Some(voice_config) = self.voice_config {
-            let coefficient = 1.0 / self.streams.len() as f32;
-            Ok((voice_config, coefficient, stream::Mixer::new(self.streams)))
-        } else {
-            Err(stream::Error::NoItems)
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.bytes().all(|b| b >= 0x20 && b <= 0x7d && b != b'=')
+}
+
+/// The comment options given to the tag command, in the order given, as the
+/// name of each option and its value.
+fn comment_options<'a>(matches: &'a clap::ArgMatches) -> Vec<(&'static str, &'a str)> {
+    let mut options = vec![];
+    for &option in ["remove", "set", "add"].iter() {
+        if let (Some(values), Some(indices)) = (matches.values_of(option),
+                                                 matches.indices_of(option)) {
+            options.extend(indices.zip(values).map(|(index, value)| (index, option, value)));
         }
     }
+    options.sort_by_key(|&(index, _, _)| index);
+    options.into_iter().map(|(_, option, value)| (option, value)).collect()
 }
 
-fn create_voice(voice_config: VoiceConfig, endpoint: cpal::Endpoint) -> cpal::Voice {
-    let format = {
-        let formats = endpoint.get_supported_formats_list();
-        let formats = insist!(formats,
-                              "fatal: failed to get list of supported audio formats");
+fn tag(matches: &clap::ArgMatches) {
+    let mut edits = vec![];
+    for (option, value) in comment_options(matches) {
+        if option == "remove" {
+            if !valid_key(value) {
+                writeln!(&mut io::stderr(), "fatal: invalid comment name '{}'", value).ok();
+                process::exit(1);
+            }
+            edits.push(Edit::Remove(value));
+            continue;
+        }
+        let (key, value) = parse_comment(value).unwrap_or_else(|| {
+            writeln!(&mut io::stderr(), "fatal: invalid comment '{}'", value).ok();
+            process::exit(1);
+        });
+        if option == "set" {
+            edits.push(Edit::Set(key, value));
+        } else {
+            edits.push(Edit::Add(key, value));
+        }
+    }
+    let mut failed = false;
+    for file in matches.values_of("file").map(|v| v.collect()).unwrap_or(vec![]) {
+        let path = path::Path::new(file);
+        if !edits.is_empty() {
+            let res = ogg::rewrite_comments(path, |comments| {
+                for edit in edits.iter() {
+                    match *edit {
+                        Edit::Remove(key) => comments.remove(key),
+                        Edit::Set(key, value) => comments.set(key, value),
+                        Edit::Add(key, value) => comments.add(key, value),
+                    }
+                }
+            });
+            if let Err(err) = res {
+                let err = digraph123::Error::File(path.to_path_buf(), Box::new(err));
+                print_error!(&err, "error: failed to rewrite comments");
+                failed = true;
+                continue;
+            }
+        }
+        println!("{}", path.display());
+        match stream::comments(path) {
+            Ok(comments) => {
+                println!("  vendor {}", comments.vendor);
+                for &(ref key, ref value) in comments.comments.iter() {
+                    println!("  {}={}", key, value);
+                }
+            }
+            Err(err) => {
+                print_error!(&err, "error: failed to read comments");
+                failed = true;
+            }
+        }
+        // Read the splice point back the way the player does.
+        match stream::splice_point(path) {
+            Ok(Some(splice_point)) => println!("  splice point {}", splice_point),
+            Ok(None) => println!("  no splice point"),
+            Err(err) => {
+                print_error!(&err, "error: failed to read splice point");
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
 
-        formats.filter(|f| f.samples_rate.0 as u32 == voice_config.1)
-               .filter(|f| f.channels.len() == voice_config.0 as usize)
-               .filter(|f| f.data_type == cpal::SampleFormat::F32)
-               .next()
-    };
-    let format = if let Some(format) = format {
-        format
+/// Writes the history and appends to the timeline files, or with `finish`
+/// writes out the rest of the timeline.
+fn save_state(history: &Option<History>, timeline: &Timeline, finish: bool) {
+    if let Some(ref history) = *history {
+        if let Err(err) = history.save() {
+            print_error!(&err, "warning: failed to save history");
+        }
+    }
+    let res = if finish {
+        timeline.finish()
     } else {
-        panic!("voice format not supported");
+        timeline.save()
     };
-
-    cpal::Voice::new(&endpoint, &format).expect("Failed to create a voice")
+    if let Err(err) = res {
+        print_error!(&err, "warning: failed to save timeline");
+    }
 }
 
 fn main() {
@@ -182,27 +542,386 @@ fn main() {
                       .version("1.0.0")
                       .author("Mattias Päivärinta")
                       .about("Play digraph shaped audio recordings using random walk")
+                      .setting(clap::AppSettings::SubcommandsNegateReqs)
                       .arg(clap::Arg::with_name("dir")
-                               .help("A digraph directory")
+                               .help("A digraph directory, or several comma separated \
+                                      directories of stems sharing one walk, optionally \
+                                      followed by *WALKERS+OFFSET to walk it WALKERS times \
+                                      with walks starting OFFSET seconds apart")
                                .index(1)
                                .required(true)
                                .multiple(true))
+                      .arg(pattern_arg())
+                      .arg(clap::Arg::with_name("tags")
+                               .help("Only play variants tagged with one of these \
+                                      (comma separated) tags, or untagged")
+                               .long("tags")
+                               .short("t")
+                               .takes_value(true))
+                      .arg(clap::Arg::with_name("intensity")
+                               .help("Start out at this intensity, between 0 and 1")
+                               .long("intensity")
+                               .short("i")
+                               .takes_value(true))
+                      .arg(clap::Arg::with_name("crossfade")
+                               .help("Crossfade each track into the next over this many \
+                                      seconds, unless an arrow or file says otherwise")
+                               .long("crossfade")
+                               .value_name("SECONDS")
+                               .takes_value(true))
+                      .arg(clap::Arg::with_name("history")
+                               .help("Favour files played fewer times according to this \
+                                      file, and record what is played in it")
+                               .long("history")
+                               .value_name("FILE")
+                               .takes_value(true))
+                      .arg(clap::Arg::with_name("timeline")
+                               .help("Write the tracks played to this file as Audacity labels \
+                                      (.txt), an edit decision list (.edl) or comma separated \
+                                      values (.csv)")
+                               .long("timeline")
+                               .value_name("FILE")
+                               .takes_value(true)
+                               .multiple(true)
+                               .number_of_values(1))
+                      .arg(clap::Arg::with_name("couple")
+                               .help("Make the layer DIR2 head for NODE2 whenever the layer \
+                                      DIR1 enters NODE1")
+                               .long("couple")
+                               .short("c")
+                               .value_name("DIR1:NODE1=DIR2:NODE2")
+                               .takes_value(true)
+                               .multiple(true)
+                               .number_of_values(1))
+                      .arg(clap::Arg::with_name("watch")
+                               .help("Reload digraph directories when their files change")
+                               .long("watch")
+                               .short("w"))
+                      .after_help("The variant part of a track file name is split on '-' and \
+                                   ',' into case insensitive tags, leaving out a leading \
+                                   number, which orders the variants instead.\n\n\
+                                   A directory containing a compiled digraph is played \
+                                   without being scanned.\n\n\
+                                   While playing, these commands are read from standard input:\n\
+                                   \x20   tags [TAG...]    restrict variants to TAGs, or lift \
+                                   the restriction\n\
+                                   \x20   goto [NODE]      head for NODE, or stop heading \
+                                   anywhere\n\
+                                   \x20   intensity [X]    adapt to intensity X, or to any \
+                                   intensity\n\
+                                   \x20   sting NAME [now|splice|beat]\n\
+                                   \x20                    play the stinger NAME now, with the \
+                                   next track or on the next beat\n\
+                                   \x20   reset [SECONDS]  start the walks over from \"start\", \
+                                   fading out over SECONDS\n\
+                                   \x20   jump NODE [SECONDS]\n\
+                                   \x20                    move the walks to NODE right away, \
+                                   fading out over SECONDS")
+                      .subcommand(clap::SubCommand::with_name("compile")
+                                      .about("Scan digraph directories and store the result in \
+                                              a file in each of them")
+                                      .arg(clap::Arg::with_name("dir")
+                                               .help("A digraph directory")
+                                               .index(1)
+                                               .required(true)
+                                               .multiple(true))
+                                      .arg(pattern_arg()))
+                      .subcommand(clap::SubCommand::with_name("info")
+                                      .about("List the nodes, arrows and files of digraph \
+                                              directories")
+                                      .arg(clap::Arg::with_name("dir")
+                                               .help("A digraph directory")
+                                               .index(1)
+                                               .required(true)
+                                               .multiple(true))
+                                      .arg(pattern_arg()))
+                      .subcommand(clap::SubCommand::with_name("seams")
+                                      .about("Play the moments around every splice of one \
+                                              arrow into the next in digraph directories")
+                                      .arg(clap::Arg::with_name("dir")
+                                               .help("A digraph directory")
+                                               .index(1)
+                                               .required(true)
+                                               .multiple(true))
+                                      .arg(pattern_arg())
+                                      .arg(clap::Arg::with_name("before")
+                                               .help("Seconds to play before each splice")
+                                               .long("before")
+                                               .value_name("SECONDS")
+                                               .takes_value(true)
+                                               .default_value("3"))
+                                      .arg(clap::Arg::with_name("after")
+                                               .help("Seconds to play after each splice")
+                                               .long("after")
+                                               .value_name("SECONDS")
+                                               .takes_value(true)
+                                               .default_value("3"))
+                                      .arg(clap::Arg::with_name("variants")
+                                               .help("Play the seams between every pair of \
+                                                      variants rather than the first variant \
+                                                      of each arrow")
+                                               .long("variants")))
+                      .subcommand(clap::SubCommand::with_name("analyze")
+                                      .about("Measure the clicks, level jumps and DC offset \
+                                              steps at every splice of one arrow into the next \
+                                              in digraph directories, most severe first")
+                                      .arg(clap::Arg::with_name("dir")
+                                               .help("A digraph directory")
+                                               .index(1)
+                                               .required(true)
+                                               .multiple(true))
+                                      .arg(pattern_arg())
+                                      .arg(clap::Arg::with_name("window")
+                                               .help("Seconds to measure on either side of \
+                                                      each splice")
+                                               .long("window")
+                                               .value_name("SECONDS")
+                                               .takes_value(true)
+                                               .default_value("0.05"))
+                                      .arg(clap::Arg::with_name("variants")
+                                               .help("Measure the seams between every pair of \
+                                                      variants rather than the first variant \
+                                                      of each arrow")
+                                               .long("variants"))
+                                      .arg(clap::Arg::with_name("all")
+                                               .help("List every seam rather than only the \
+                                                      suspect ones")
+                                               .long("all")))
+                      .subcommand(clap::SubCommand::with_name("splice")
+                                      .about("Suggest a splice point for every track file of \
+                                              digraph directories where its music gives way \
+                                              to its tail")
+                                      .arg(clap::Arg::with_name("dir")
+                                               .help("A digraph directory, or the stem \
+                                                      directories of a layer separated by \
+                                                      commas")
+                                               .index(1)
+                                               .required(true)
+                                               .multiple(true))
+                                      .arg(pattern_arg())
+                                      .arg(clap::Arg::with_name("comment")
+                                               .help("Only print each file followed by the \
+                                                      SPLICEPOINT comment to add to it")
+                                               .long("comment")))
+                      .subcommand(clap::SubCommand::with_name("tag")
+                                      .about("List or change the Vorbis comments of track \
+                                              files in place, without reencoding them")
+                                      .arg(clap::Arg::with_name("file")
+                                               .help("An Ogg Vorbis file")
+                                               .index(1)
+                                               .required(true)
+                                               .multiple(true))
+                                      .arg(clap::Arg::with_name("remove")
+                                               .help("Remove the comments named KEY")
+                                               .long("remove")
+                                               .short("r")
+                                               .value_name("KEY")
+                                               .takes_value(true)
+                                               .multiple(true)
+                                               .number_of_values(1))
+                                      .arg(clap::Arg::with_name("set")
+                                               .help("Replace the comments named KEY with this \
+                                                      one")
+                                               .long("set")
+                                               .short("s")
+                                               .value_name("KEY=VALUE")
+                                               .takes_value(true)
+                                               .multiple(true)
+                                               .number_of_values(1))
+                                      .arg(clap::Arg::with_name("add")
+                                               .help("Add this comment")
+                                               .long("add")
+                                               .short("a")
+                                               .value_name("KEY=VALUE")
+                                               .takes_value(true)
+                                               .multiple(true)
+                                               .number_of_values(1)))
                       .get_matches();
 
-    let mut mixer_builder = MixerBuilder::new();
+    match matches.subcommand() {
+        ("compile", Some(sub_matches)) => compile(sub_matches),
+        ("info", Some(sub_matches)) => info(sub_matches),
+        ("seams", Some(sub_matches)) => seams(sub_matches),
+        ("analyze", Some(sub_matches)) => analyze(sub_matches),
+        ("splice", Some(sub_matches)) => splice(sub_matches),
+        ("tag", Some(sub_matches)) => tag(sub_matches),
+        _ => play(&matches),
+    }
+}
+
+fn play(matches: &clap::ArgMatches) {
+    let grammar = grammar(matches);
+
+    let mut mixer_builder = MixerBuilder::new(&grammar);
+    let history = matches.value_of("history").map(|path| {
+        insist!(History::load(path), "fatal: failed to load history")
+    });
+    if let Some(ref history) = history {
+        mixer_builder.history(history.clone());
+    }
+    let timeline = Timeline::new();
+    let timeline_files: Vec<&str> = matches.values_of("timeline")
+                                           .map(|v| v.collect())
+                                           .unwrap_or(vec![]);
+    for &file in timeline_files.iter() {
+        let path = path::Path::new(file);
+        match timeline::Format::from_path(path) {
+            Some(format) => {
+                insist!(timeline.add_file(path, format), "fatal: failed to create timeline")
+            }
+            None => {
+                writeln!(&mut io::stderr(), "fatal: unknown timeline format '{}'", file).ok();
+                process::exit(1);
+            }
+        }
+    }
+    if !timeline_files.is_empty() {
+        mixer_builder.timeline(timeline.clone());
+    }
+    if let Some(intensity) = matches.value_of("intensity") {
+        match f64::from_str(intensity) {
+            Ok(value) if value >= 0.0 && value <= 1.0 => {
+                mixer_builder.intensity(Some(value));
+            }
+            _ => {
+                writeln!(&mut io::stderr(), "fatal: invalid intensity '{}'", intensity).ok();
+                process::exit(1);
+            }
+        }
+    }
+    if matches.is_present("crossfade") {
+        mixer_builder.crossfade(Some(seconds_arg(&matches, "crossfade")));
+    }
     for dir in matches.values_of("dir").map(|v| v.collect()).unwrap_or(vec![]) {
-        if let Err(err) = mixer_builder.dir(dir) {
-            print_error!(&err, "warning: ignoring directory");
+        if let Some((stems, walkers, offset)) = parse_layer(dir) {
+            if let Err(err) = mixer_builder.canon(&stems, walkers, offset) {
+                print_error!(&err, "warning: ignoring directory");
+            }
+        } else {
+            writeln!(&mut io::stderr(), "warning: ignoring malformed directory '{}'", dir).ok();
+        }
+        for warning in mixer_builder.take_warnings() {
+            print_error!(&warning, "warning: ignoring file");
+        }
+    }
+    for rule in matches.values_of("couple").map(|v| v.collect()).unwrap_or(vec![]) {
+        if let Some((source, node, target, target_node)) = parse_coupling(rule) {
+            if let Err(err) = mixer_builder.couple(source, node, target, target_node) {
+                print_error!(&err, "warning: ignoring coupling '{}'", rule);
+            }
+        } else {
+            writeln!(&mut io::stderr(), "warning: ignoring malformed coupling '{}'", rule).ok();
+        }
+    }
+    let (voice_config, coefficient, mut mixer, layers) =
+        insist!(mixer_builder.build(), "fatal: failed to construct mixer");
+    if let Some(tags) = matches.value_of("tags") {
+        for steering in layers.iter().flat_map(|layer| layer.steerings()) {
+            steering.set_tags(Some(digraph::parse_tags(tags)));
+        }
+    }
+    let mut watched = vec![];
+    let mut watched_layers = vec![];
+    if matches.is_present("watch") {
+        for layer in layers.iter() {
+            let mut watchers = vec![];
+            for dir in layer.dirs() {
+                match watch::DirWatcher::new(dir) {
+                    Ok(watcher) => watchers.push(watcher),
+                    Err(err) => print_error!(&err, "warning: not watching directory '{}'", dir),
+                }
+            }
+            if !watchers.is_empty() {
+                watched.push((layer.dirs().to_vec(), watchers));
+                watched_layers.push(layer);
+            }
         }
     }
-    let (voice_config, coefficient, mut mixer) = insist!(mixer_builder.build(),
-                                                         "fatal: failed to construct mixer");
+    let reloads = watch::spawn(watched, grammar.clone(), history.is_some());
+    let mut last_save = time::Instant::now();
     let num_channels = voice_config.0 as usize;
 
     let endpoint = cpal::get_default_endpoint().expect("default endpoing");
     let mut voice = create_voice(voice_config, endpoint);
+    let commands = command::spawn_reader();
 
     while !mixer.is_eos() {
+        while let Ok(line) = commands.try_recv() {
+            match command::Command::parse(&line) {
+                Ok(command::Command::Sting(name, quantize)) => {
+                    // A stinger found in several layers is played once, by
+                    // the first of them.
+                    match layers.iter().find(|l| l.stingers().contains(&name.as_str())) {
+                        Some(layer) => {
+                            if let Err(err) = layer.sting(&mut mixer, &name, quantize) {
+                                print_error!(&err, "warning: failed to play stinger '{}'", name);
+                            }
+                        }
+                        None => {
+                            let err = digraph123::Error::NoStinger(name);
+                            print_error!(&err, "warning: ignoring command");
+                        }
+                    }
+                }
+                Ok(command::Command::Reset(fade)) => {
+                    for layer in layers.iter() {
+                        layer.reset(fade);
+                    }
+                }
+                Ok(command::Command::Jump(node, fade)) => {
+                    let jumped = layers.iter()
+                                       .filter(|layer| layer.jump(&node, fade).is_ok())
+                                       .count();
+                    if jumped == 0 {
+                        let err = digraph123::Error::NoNode(node);
+                        print_error!(&err, "warning: ignoring command");
+                    }
+                }
+                Ok(command::Command::Goto(node)) => {
+                    for layer in layers.iter() {
+                        let node = node.as_ref()
+                                       .map(|node| layer.node(node).unwrap_or(node.clone()));
+                        for steering in layer.steerings() {
+                            steering.request(node.clone());
+                        }
+                    }
+                }
+                Ok(command) => {
+                    for steering in layers.iter().flat_map(|layer| layer.steerings()) {
+                        command.apply(steering);
+                    }
+                }
+                Err(err) => print_error!(&err, "warning: ignoring command"),
+            }
+        }
+
+        if last_save.elapsed() >= time::Duration::from_secs(10) {
+            last_save = time::Instant::now();
+            save_state(&history, &timeline, false);
+        }
+
+        while let Ok(reload) = reloads.try_recv() {
+            let layer = watched_layers[reload.layer];
+            for warning in reload.warnings {
+                print_error!(&warning, "warning: ignoring file");
+            }
+            if let (Some(history), Some(identities)) = (history.as_ref(), reload.identities) {
+                history.learn(identities);
+            }
+            match reload.result.and_then(|(voice_config, digraph)| {
+                layer.replace(voice_config, digraph)
+            }) {
+                Ok(()) => {}
+                Err(err) => print_error!(&err, "warning: keeping previous digraph"),
+            }
+        }
+
+        for layer in layers.iter() {
+            for err in layer.take_skipped() {
+                print_error!(&err, "warning: skipping track");
+            }
+        }
+
         let max_read = mixer.max_read();
         assert_eq!(max_read % num_channels, 0);
 
@@ -241,5 +960,6 @@ fn main() {
     while voice.get_pending_samples() > 0 {
         thread::sleep(time::Duration::from_millis(100));
     }
+    save_state(&history, &timeline, true);
 
 }
@@ -0,0 +1,281 @@
+use digraph;
+use grammar;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::path;
+use stream;
+use syntax;
+use syntax::{option, parse_non_negative, parse_probability, parse_value};
+
+/// Name of the optional file configuring the digraph of a directory.
+///
+/// Each line is either blank, a comment starting with '#', or a directive:
+///
+/// ```text
+/// pattern ^(?P<tail>\w+)_(?P<head>\w+)(?:_(?P<variant>.+))?\.ogg$
+/// node woods stay=0.7 min=2 max=5
+/// arrow woods village weight=2
+/// arrow start intro limit=1
+/// arrow verse verse order=cycle
+/// arrow woods battle intensity=0.6-1
+/// arrow woods finale after=1200 dwell=60
+/// arrow woods village crossfade=2.5
+/// tag calm intensity=0-0.4
+/// layer intensity=0.3-1 tempo=120
+/// ```
+pub const FILE_NAME: &'static str = "digraph.manifest";
+
+fn options<'a, I>(line: usize, words: I) -> stream::Result<Vec<(&'a str, &'a str)>>
+    where I: Iterator<Item = &'a str>
+{
+    let mut options = vec![];
+    for word in words {
+        options.push(try!(option(line, word)));
+    }
+    Ok(options)
+}
+
+fn directive(builder: &mut digraph::DigraphBuilder,
+             grammar: &grammar::Grammar,
+             line: usize,
+             text: &str)
+             -> stream::Result<()> {
+    let mut words = text.split_whitespace();
+    match words.next() {
+        Some("pattern") => Ok(()),
+        Some("node") => {
+            let name = grammar.name(try!(words.next().ok_or_else(|| {
+                syntax::error(line, "missing node name".to_string())
+            })));
+            for (key, value) in try!(options(line, words)) {
+                match key {
+                    "stay" => {
+                        builder.stay(&name, try!(parse_probability(line, key, value)));
+                    }
+                    "min" => {
+                        builder.min_dwell(&name, try!(parse_value(line, key, value)));
+                    }
+                    "max" => {
+                        builder.max_dwell(&name, try!(parse_value(line, key, value)));
+                    }
+                    _ => return Err(syntax::error(line, format!("unknown node option '{}'", key))),
+                }
+            }
+            try!(syntax::check_dwell(line, builder, &name));
+            Ok(())
+        }
+        Some("arrow") => {
+            let (tail, head) = match (words.next(), words.next()) {
+                (Some(tail), Some(head)) => (grammar.name(tail), grammar.name(head)),
+                _ => return Err(syntax::error(line, "missing arrow tail or head".to_string())),
+            };
+            for (key, value) in try!(options(line, words)) {
+                match key {
+                    "weight" => {
+                        builder.weight(&tail, &head, try!(parse_non_negative(line, key, value)));
+                    }
+                    "limit" => {
+                        builder.limit(&tail, &head, try!(parse_value(line, key, value)));
+                    }
+                    "order" => {
+                        builder.order(&tail, &head, try!(parse_value(line, key, value)));
+                    }
+                    "intensity" => {
+                        builder.intensity(&tail, &head, try!(parse_value(line, key, value)));
+                    }
+                    "after" => {
+                        builder.after(&tail, &head, try!(parse_non_negative(line, key, value)));
+                    }
+                    "dwell" => {
+                        let seconds = try!(parse_non_negative(line, key, value));
+                        builder.after_dwell(&tail, &head, seconds);
+                    }
+                    "crossfade" => {
+                        let seconds = try!(parse_non_negative(line, key, value));
+                        builder.crossfade(&tail, &head, seconds);
+                    }
+                    _ => return Err(syntax::error(line, format!("unknown arrow option '{}'", key))),
+                }
+            }
+            Ok(())
+        }
+        Some("tag") => {
+            let tag = match words.next() {
+                Some(tag) => tag.to_lowercase(),
+                None => return Err(syntax::error(line, "missing tag name".to_string())),
+            };
+            for (key, value) in try!(options(line, words)) {
+                match key {
+                    "intensity" => {
+                        builder.tag_intensity(&tag, try!(parse_value(line, key, value)));
+                    }
+                    _ => return Err(syntax::error(line, format!("unknown tag option '{}'", key))),
+                }
+            }
+            Ok(())
+        }
+        Some("layer") => {
+            for (key, value) in try!(options(line, words)) {
+                match key {
+                    "intensity" => {
+                        builder.layer_intensity(try!(parse_value(line, key, value)));
+                    }
+                    "tempo" => {
+                        let bpm: f64 = try!(parse_value(line, key, value));
+                        if bpm > 0.0 {
+                            builder.tempo(bpm);
+                        } else {
+                            return Err(syntax::error(line, format!("'{}' must be positive", key)));
+                        }
+                    }
+                    _ => return Err(syntax::error(line, format!("unknown layer option '{}'", key))),
+                }
+            }
+            Ok(())
+        }
+        Some(word) => Err(syntax::error(line, format!("unknown directive '{}'", word))),
+        None => Ok(()),
+    }
+}
+
+/// Reads the file name pattern set by the manifest at `path`, if any.
+pub fn grammar(path: &path::Path) -> stream::Result<Option<grammar::Grammar>> {
+    let file = try!(fs::File::open(path));
+    let mut grammar = None;
+    for (i, line) in io::BufReader::new(file).lines().enumerate() {
+        let line = try!(line);
+        let text = line.splitn(2, '#').next().unwrap_or("").trim();
+        let mut words = text.splitn(2, char::is_whitespace);
+        if words.next() == Some("pattern") {
+            match words.next().map(str::trim) {
+                Some(pattern) if !pattern.is_empty() => {
+                    grammar = Some(try!(grammar::Grammar::new(pattern)));
+                }
+                _ => return Err(syntax::error(i + 1, "missing pattern".to_string())),
+            }
+        }
+    }
+    Ok(grammar)
+}
+
+/// Applies the manifest at `path` to `builder`.
+pub fn load(path: &path::Path, builder: &mut digraph::DigraphBuilder) -> stream::Result<()> {
+    load_with_grammar(path, &grammar::Grammar::default(), builder)
+}
+
+/// Applies the manifest at `path` to `builder`, spelling node names the way
+/// `grammar` does.
+pub fn load_with_grammar(path: &path::Path,
+                         grammar: &grammar::Grammar,
+                         builder: &mut digraph::DigraphBuilder)
+                         -> stream::Result<()> {
+    let file = try!(fs::File::open(path));
+    for (i, line) in io::BufReader::new(file).lines().enumerate() {
+        let line = try!(line);
+        let text = line.splitn(2, '#').next().unwrap_or("");
+        try!(directive(builder, grammar, i + 1, text));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digraph::{Digraph, DigraphBuilder, Order, Span, Variant};
+    use std::env;
+    use std::io::Write;
+
+    fn apply(lines: &[&str]) -> stream::Result<DigraphBuilder> {
+        let mut builder = DigraphBuilder::new();
+        for (i, line) in lines.iter().enumerate() {
+            try!(directive(&mut builder, &grammar::Grammar::default(), i + 1, line));
+        }
+        Ok(builder)
+    }
+
+    fn error_line(lines: &[&str]) -> Option<usize> {
+        match apply(lines) {
+            Err(stream::Error::Syntax(line, _)) => Some(line),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn directives_configure_the_digraph() {
+        let mut builder = apply(&["node Woods stay=0.7 min=2 max=5",
+                                  "arrow woods Village weight=2 limit=1 order=cycle",
+                                  "arrow woods village intensity=0.6-1 after=1200 dwell=60",
+                                  "arrow woods village crossfade=2.5",
+                                  "tag Calm intensity=0-0.4",
+                                  "layer intensity=0.3-1 tempo=120",
+                                  "",
+                                  "pattern ignored here"])
+                              .unwrap();
+        builder.arrow("woods".to_string(),
+                      "village".to_string(),
+                      Variant::new(path::PathBuf::from("woods-village.ogg"), None));
+        let digraph: Digraph = builder.into();
+        let woods = &digraph.nodes()[digraph.index("woods").unwrap()];
+        assert_eq!((woods.stay(), woods.min_dwell(), woods.max_dwell()),
+                   (Some(0.7), Some(2), Some(5)));
+        let arrow = &woods.arrows()[0];
+        assert_eq!(digraph.nodes()[arrow.head()].name(), "village");
+        assert_eq!(arrow.weight(), 2.0);
+        assert_eq!(arrow.limit(), Some(1));
+        assert!(arrow.order() == Order::Cycle);
+        assert_eq!(arrow.intensity(),
+                   Some(Span {
+                       low: 0.6,
+                       high: 1.0,
+                   }));
+        assert_eq!((arrow.after(), arrow.after_dwell(), arrow.crossfade()),
+                   (Some(1200.0), Some(60.0), Some(2.5)));
+        assert_eq!(digraph.tag_intensities().get("calm").map(|span| span.high), Some(0.4));
+        assert_eq!(digraph.intensity().map(|span| span.low), Some(0.3));
+        assert_eq!(digraph.tempo(), Some(120.0));
+    }
+
+    #[test]
+    fn invalid_directives_report_their_line() {
+        assert_eq!(error_line(&["", "sing woods"]), Some(2));
+        assert_eq!(error_line(&["node"]), Some(1));
+        assert_eq!(error_line(&["node woods stay=1.5"]), Some(1));
+        assert_eq!(error_line(&["node woods loud=1"]), Some(1));
+        assert_eq!(error_line(&["arrow woods"]), Some(1));
+        assert_eq!(error_line(&["arrow woods village weight=-1"]), Some(1));
+        assert_eq!(error_line(&["arrow woods village order=shuffle"]), Some(1));
+        assert_eq!(error_line(&["arrow woods village intensity=1-0"]), Some(1));
+        assert_eq!(error_line(&["tag calm weight"]), Some(1));
+        assert_eq!(error_line(&["layer tempo=0"]), Some(1));
+    }
+
+    #[test]
+    fn minimum_dwell_may_not_exceed_maximum() {
+        assert_eq!(error_line(&["node woods min=3", "node woods max=2"]), Some(2));
+        assert_eq!(error_line(&["node woods max=2 min=3"]), Some(1));
+        assert!(apply(&["node woods min=2 max=2"]).is_ok());
+    }
+
+    #[test]
+    fn pattern_spells_the_node_names() {
+        let path = env::temp_dir().join("digraph123-manifest-pattern");
+        {
+            let mut file = fs::File::create(&path).unwrap();
+            writeln!(file, "# Nodes keep their case.").unwrap();
+            writeln!(file, r"pattern ^(?P<tail>[^_]+)_(?P<head>[^_]+)\.ogg$").unwrap();
+            writeln!(file, "node Woods stay=0.5 # Mostly stays.").unwrap();
+        }
+        let grammar = grammar(&path).unwrap().unwrap();
+        let mut builder = DigraphBuilder::new();
+        let res = load_with_grammar(&path, &grammar, &mut builder);
+        fs::remove_file(&path).ok();
+        res.unwrap();
+        builder.arrow("Woods".to_string(),
+                      "Woods".to_string(),
+                      Variant::new(path::PathBuf::from("Woods_Woods.ogg"), None));
+        let digraph: Digraph = builder.into();
+        assert_eq!(digraph.index("Woods").map(|i| digraph.nodes()[i].stay()),
+                   Some(Some(0.5)));
+    }
+}
@@ -0,0 +1,597 @@
+//! Just enough of the Ogg framing (RFC 3533) and the Vorbis headers to read
+//! the format and length of a file and to rewrite its comments.
+//!
+//! The Vorbis decoder only hands out decoded packets: the length of a file
+//! would take decoding all of it, and it cannot write comments at all. Both
+//! only need the pages and the header packets, which are read here without
+//! touching the audio.
+
+use std::cmp;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path;
+use stream;
+
+const CAPTURE_PATTERN: &'static [u8] = b"OggS";
+const VORBIS: &'static [u8] = b"vorbis";
+
+const CONTINUED: u8 = 0x01;
+const BEGINNING_OF_STREAM: u8 = 0x02;
+/// Granule position of a page on which no packet ends.
+const NO_GRANULE: u64 = 0xffffffffffffffff;
+const MAX_SEGMENTS: usize = 255;
+
+fn malformed(message: &str) -> stream::Error {
+    stream::Error::Ogg(message.to_string())
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+fn put_le(bytes: &mut Vec<u8>, value: u64, len: usize) {
+    for i in 0..len {
+        bytes.push((value >> (8 * i)) as u8);
+    }
+}
+
+/// The CRC of an Ogg page: polynomial 0x04c11db7, no reflection, starting at
+/// zero and without a final XOR.
+fn crc(bytes: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in bytes {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04c11db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Fills `buf`, or returns false if the reader is exhausted before the
+/// first byte.
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated page")),
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+/// An Ogg page.
+pub struct Page {
+    pub header_type: u8,
+    pub granule_position: u64,
+    pub serial: u32,
+    pub sequence: u32,
+    pub lacing: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl Page {
+    pub fn read<R: Read>(reader: &mut R) -> stream::Result<Option<Page>> {
+        let mut header = [0u8; 27];
+        if !try!(read_or_eof(reader, &mut header)) {
+            return Ok(None);
+        }
+        if &header[0..4] != CAPTURE_PATTERN || header[4] != 0 {
+            return Err(malformed("missing page capture pattern"));
+        }
+        let mut lacing = vec![0u8; header[26] as usize];
+        try!(reader.read_exact(&mut lacing));
+        let mut data = vec![0u8; lacing.iter().map(|&l| l as usize).sum()];
+        try!(reader.read_exact(&mut data));
+        Ok(Some(Page {
+            header_type: header[5],
+            granule_position: le_u64(&header[6..14]),
+            serial: le_u32(&header[14..18]),
+            sequence: le_u32(&header[18..22]),
+            lacing: lacing,
+            data: data,
+        }))
+    }
+
+    /// Writes the page with a freshly computed CRC.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(27 + self.lacing.len() + self.data.len());
+        bytes.extend_from_slice(CAPTURE_PATTERN);
+        bytes.push(0);
+        bytes.push(self.header_type);
+        put_le(&mut bytes, self.granule_position, 8);
+        put_le(&mut bytes, self.serial as u64, 4);
+        put_le(&mut bytes, self.sequence as u64, 4);
+        put_le(&mut bytes, 0, 4);
+        bytes.push(self.lacing.len() as u8);
+        bytes.extend_from_slice(&self.lacing);
+        bytes.extend_from_slice(&self.data);
+        let crc = crc(&bytes);
+        for i in 0..4 {
+            bytes[22 + i] = (crc >> (8 * i)) as u8;
+        }
+        writer.write_all(&bytes)
+    }
+
+    /// Appends the segments of this page to `packet`, pushing each completed
+    /// packet onto `packets`.
+    fn split_packets(&self, packet: &mut Vec<u8>, packets: &mut Vec<Vec<u8>>) {
+        let mut offset = 0;
+        for &l in self.lacing.iter() {
+            packet.extend_from_slice(&self.data[offset..offset + l as usize]);
+            offset += l as usize;
+            if l < 255 {
+                packets.push(packet.split_off(0));
+            }
+        }
+    }
+}
+
+/// Reads pages until the three Vorbis header packets are complete.
+///
+/// Returns the header packets along with the pages they were read from.
+pub fn read_headers<R: Read>(reader: &mut R) -> stream::Result<(Vec<Vec<u8>>, Vec<Page>)> {
+    let mut pages = vec![];
+    let mut packets = vec![];
+    let mut packet = vec![];
+    while packets.len() < 3 {
+        if let Some(page) = try!(Page::read(reader)) {
+            if pages.first().map_or(false, |first: &Page| first.serial != page.serial) {
+                continue;
+            }
+            page.split_packets(&mut packet, &mut packets);
+            pages.push(page);
+        } else {
+            return Err(malformed("missing Vorbis headers"));
+        }
+    }
+    try!(check_headers(&packets));
+    Ok((packets, pages))
+}
+
+fn check_headers(packets: &[Vec<u8>]) -> stream::Result<()> {
+    for (i, packet) in packets.iter().enumerate() {
+        if packet.len() < 7 || packet[0] != 2 * i as u8 + 1 || &packet[1..7] != VORBIS {
+            return Err(malformed("invalid Vorbis header"));
+        }
+    }
+    Ok(())
+}
+
+/// Lays out packets on pages of `serial` numbered from 0, the first packet
+/// alone on the first page of the stream.
+fn paginate(packets: &[Vec<u8>], serial: u32) -> Vec<Page> {
+    let mut pages = vec![];
+    for group in vec![&packets[..1], &packets[1..]] {
+        let mut page = Page {
+            header_type: 0,
+            granule_position: NO_GRANULE,
+            serial: serial,
+            sequence: 0,
+            lacing: vec![],
+            data: vec![],
+        };
+        for packet in group {
+            let mut offset = 0;
+            loop {
+                let len = cmp::min(255, packet.len() - offset);
+                page.lacing.push(len as u8);
+                page.data.extend_from_slice(&packet[offset..offset + len]);
+                offset += len;
+                if len < 255 {
+                    // Header packets carry no samples.
+                    page.granule_position = 0;
+                }
+                if page.lacing.len() == MAX_SEGMENTS {
+                    let continued = if len == 255 {
+                        CONTINUED
+                    } else {
+                        0
+                    };
+                    pages.push(page);
+                    page = Page {
+                        header_type: continued,
+                        granule_position: NO_GRANULE,
+                        serial: serial,
+                        sequence: 0,
+                        lacing: vec![],
+                        data: vec![],
+                    };
+                }
+                if len < 255 {
+                    break;
+                }
+            }
+        }
+        if !page.lacing.is_empty() {
+            pages.push(page);
+        }
+    }
+    for (i, page) in pages.iter_mut().enumerate() {
+        page.sequence = i as u32;
+    }
+    if let Some(first) = pages.first_mut() {
+        first.header_type |= BEGINNING_OF_STREAM;
+    }
+    pages
+}
+
+/// The Vorbis comments of a stream.
+pub struct Comments {
+    pub vendor: String,
+    pub comments: Vec<(String, String)>,
+}
+
+impl Comments {
+    pub fn parse(packet: &[u8]) -> stream::Result<Comments> {
+        fn string(packet: &[u8], offset: &mut usize) -> stream::Result<String> {
+            if packet.len() < *offset + 4 {
+                return Err(malformed("truncated comment header"));
+            }
+            let len = le_u32(&packet[*offset..*offset + 4]) as usize;
+            *offset += 4;
+            if packet.len() < *offset + len {
+                return Err(malformed("truncated comment header"));
+            }
+            let s = String::from_utf8_lossy(&packet[*offset..*offset + len]).into_owned();
+            *offset += len;
+            Ok(s)
+        }
+
+        let mut offset = 7;
+        let vendor = try!(string(packet, &mut offset));
+        if packet.len() < offset + 4 {
+            return Err(malformed("truncated comment header"));
+        }
+        let count = le_u32(&packet[offset..offset + 4]);
+        offset += 4;
+        let mut comments = vec![];
+        for _ in 0..count {
+            let comment = try!(string(packet, &mut offset));
+            let mut parts = comment.splitn(2, '=');
+            let key = parts.next().unwrap_or("").to_string();
+            let value = parts.next().unwrap_or("").to_string();
+            comments.push((key, value));
+        }
+        Ok(Comments {
+            vendor: vendor,
+            comments: comments,
+        })
+    }
+
+    /// The comment header packet holding the comments.
+    pub fn to_packet(&self) -> Vec<u8> {
+        let mut packet = vec![3];
+        packet.extend_from_slice(VORBIS);
+        put_le(&mut packet, self.vendor.len() as u64, 4);
+        packet.extend_from_slice(self.vendor.as_bytes());
+        put_le(&mut packet, self.comments.len() as u64, 4);
+        for &(ref key, ref value) in self.comments.iter() {
+            let comment = format!("{}={}", key, value);
+            put_le(&mut packet, comment.len() as u64, 4);
+            packet.extend_from_slice(comment.as_bytes());
+        }
+        // Framing bit.
+        packet.push(1);
+        packet
+    }
+
+    /// Removes the comments named `key`, which is case insensitive.
+    pub fn remove(&mut self, key: &str) {
+        self.comments.retain(|&(ref k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    pub fn add(&mut self, key: &str, value: &str) {
+        self.comments.push((key.to_string(), value.to_string()));
+    }
+
+    /// Replaces the comments named `key` with a single one.
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.add(key, value);
+    }
+
+    /// Values of the comments named `key`, which is case insensitive.
+    pub fn get(&self, key: &str) -> Vec<&str> {
+        self.comments
+            .iter()
+            .filter(|&&(ref k, _)| k.eq_ignore_ascii_case(key))
+            .map(|&(_, ref v)| v.as_str())
+            .collect()
+    }
+}
+
+/// Format, length and comments of an Ogg Vorbis file.
+///
+/// The number of channels and the sample rate are never zero.
+pub struct Info {
+    pub channels: u8,
+    pub rate: u32,
+    pub frames: u64,
+    pub comments: Comments,
+}
+
+impl Info {
+    pub fn seconds(&self) -> f64 {
+        self.frames as f64 / self.rate as f64
+    }
+}
+
+/// Reads the headers of an Ogg Vorbis file and skims its pages for its length.
+pub fn info(path: &path::Path) -> stream::Result<Info> {
+    let mut reader = io::BufReader::new(try!(fs::File::open(path)));
+    let (packets, pages) = try!(read_headers(&mut reader));
+    let identification = &packets[0];
+    if identification.len() < 16 {
+        return Err(malformed("truncated identification header"));
+    }
+    let channels = identification[11];
+    let rate = le_u32(&identification[12..16]);
+    if channels == 0 || rate == 0 {
+        return Err(malformed("no channels or zero sample rate"));
+    }
+    let serial = pages[0].serial;
+    let mut frames = 0;
+    while let Some(page) = try!(Page::read(&mut reader)) {
+        if page.serial == serial && page.granule_position != u64::max_value() {
+            frames = page.granule_position;
+        }
+    }
+    Ok(Info {
+        channels: channels,
+        rate: rate,
+        frames: frames,
+        comments: try!(Comments::parse(&packets[1])),
+    })
+}
+
+/// Rewrites the comments of an Ogg Vorbis file in place as changed by
+/// `edit`, without reencoding its audio.
+///
+/// The header packets are laid out on new pages and the pages after them
+/// are renumbered to follow. Files with several logical streams are not
+/// supported.
+pub fn rewrite_comments<F>(path: &path::Path, edit: F) -> stream::Result<()>
+    where F: FnOnce(&mut Comments)
+{
+    let mut pages = vec![];
+    {
+        let mut reader = io::BufReader::new(try!(fs::File::open(path)));
+        while let Some(page) = try!(Page::read(&mut reader)) {
+            if pages.first().map_or(false, |first: &Page| first.serial != page.serial) {
+                return Err(malformed("several logical streams"));
+            }
+            pages.push(page);
+        }
+    }
+    let mut packets = vec![];
+    let mut packet = vec![];
+    let mut header_pages = 0;
+    for page in pages.iter() {
+        if packets.len() >= 3 {
+            break;
+        }
+        page.split_packets(&mut packet, &mut packets);
+        header_pages += 1;
+    }
+    if packets.len() < 3 {
+        return Err(malformed("missing Vorbis headers"));
+    }
+    if packets.len() > 3 || !packet.is_empty() {
+        return Err(malformed("audio on a header page"));
+    }
+    try!(check_headers(&packets));
+
+    let mut comments = try!(Comments::parse(&packets[1]));
+    edit(&mut comments);
+    packets[1] = comments.to_packet();
+    let headers = paginate(&packets, pages[0].serial);
+    let renumber = headers.len() as u32;
+
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    let temporary = path::PathBuf::from(temporary);
+    let res = fs::File::create(&temporary).and_then(|file| {
+        let mut writer = io::BufWriter::new(file);
+        for page in headers.iter() {
+            try!(page.write(&mut writer));
+        }
+        for page in pages.iter_mut().skip(header_pages) {
+            page.sequence = page.sequence.wrapping_sub(header_pages as u32).wrapping_add(renumber);
+            try!(page.write(&mut writer));
+        }
+        writer.flush()
+    });
+    let res = res.and_then(|_| fs::metadata(path))
+                 .and_then(|metadata| fs::set_permissions(&temporary, metadata.permissions()))
+                 .and_then(|_| fs::rename(&temporary, path));
+    if res.is_err() {
+        fs::remove_file(&temporary).ok();
+    }
+    res.map_err(stream::Error::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::io::Write;
+    use std::path;
+
+    /// Writes a stream of a single empty audio packet of `frames` frames.
+    fn write_stream(name: &str, channels: u8, rate: u32, frames: u64) -> path::PathBuf {
+        let mut identification = vec![1];
+        identification.extend_from_slice(VORBIS);
+        put_le(&mut identification, 0, 4);
+        identification.push(channels);
+        put_le(&mut identification, rate as u64, 4);
+        identification.extend_from_slice(&[0; 12]);
+        identification.extend_from_slice(&[0xb8, 1]);
+        let comments = Comments {
+            vendor: "test".to_string(),
+            comments: vec![("TITLE".to_string(), "test".to_string())],
+        };
+        let mut setup = vec![5];
+        setup.extend_from_slice(VORBIS);
+        let packets = vec![identification, comments.to_packet(), setup];
+        let mut pages = paginate(&packets, 7);
+        let sequence = pages.len() as u32;
+        pages.push(Page {
+            header_type: 0,
+            granule_position: frames,
+            serial: 7,
+            sequence: sequence,
+            lacing: vec![1],
+            data: vec![0],
+        });
+        let path = env::temp_dir().join(format!("digraph123-ogg-{}.ogg", name));
+        let mut file = fs::File::create(&path).unwrap();
+        for page in pages.iter() {
+            page.write(&mut file).unwrap();
+        }
+        file.flush().unwrap();
+        path
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc(b"123456789"), 0x89a1897f);
+    }
+
+    #[test]
+    fn page_write_matches_known_page() {
+        let page = Page {
+            header_type: BEGINNING_OF_STREAM,
+            granule_position: 0,
+            serial: 0x12345678,
+            sequence: 0,
+            lacing: vec![3],
+            data: b"abc".to_vec(),
+        };
+        let mut bytes = vec![];
+        page.write(&mut bytes).unwrap();
+        assert_eq!(bytes,
+                   vec![0x4f, 0x67, 0x67, 0x53, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                        0x00, 0x00, 0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0xeb, 0x06,
+                        0x31, 0xbd, 0x01, 0x03, 0x61, 0x62, 0x63]);
+
+        let read = Page::read(&mut &bytes[..]).unwrap().unwrap();
+        assert_eq!(read.header_type, BEGINNING_OF_STREAM);
+        assert_eq!(read.serial, 0x12345678);
+        assert_eq!(read.lacing, vec![3]);
+        assert_eq!(read.data, b"abc".to_vec());
+        assert!(Page::read(&mut &bytes[bytes.len()..]).unwrap().is_none());
+    }
+
+    #[test]
+    fn info_reads_format_and_length() {
+        let path = write_stream("info", 2, 44100, 88200);
+        let info = info(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.rate, 44100);
+        assert_eq!(info.frames, 88200);
+        assert_eq!(info.seconds(), 2.0);
+        assert_eq!(info.comments.get("title"), vec!["test"]);
+    }
+
+    #[test]
+    fn info_rejects_zero_rate() {
+        let path = write_stream("zero-rate", 2, 0, 100);
+        let res = info(&path);
+        fs::remove_file(&path).ok();
+        assert!(res.is_err());
+    }
+
+    /// Reads the pages of a file, checking that each is written back as read,
+    /// CRC included.
+    fn read_pages(path: &path::Path) -> Vec<Page> {
+        let mut bytes = vec![];
+        fs::File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        let mut reader = &bytes[..];
+        let mut pages = vec![];
+        let mut written = vec![];
+        while let Some(page) = Page::read(&mut reader).unwrap() {
+            page.write(&mut written).unwrap();
+            pages.push(page);
+        }
+        assert!(written == bytes);
+        pages
+    }
+
+    fn join_packets(pages: &[Page]) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        let mut packet = vec![];
+        for page in pages.iter() {
+            page.split_packets(&mut packet, &mut packets);
+        }
+        assert!(packet.is_empty());
+        packets
+    }
+
+    #[test]
+    fn paginate_ends_a_multiple_of_255_with_an_empty_segment() {
+        let packets = vec![vec![1; 30], vec![3; 510], vec![5; 10]];
+        let pages = paginate(&packets, 7);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[1].lacing, vec![255, 255, 0, 10]);
+        assert!(join_packets(&pages) == packets);
+    }
+
+    #[test]
+    fn paginate_continues_packets_past_255_segments() {
+        let packets = vec![vec![1; 30], vec![3; 255 * 300], vec![5; 10]];
+        let pages = paginate(&packets, 7);
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].header_type, BEGINNING_OF_STREAM);
+        assert_eq!(pages[0].lacing, vec![30]);
+        assert_eq!(pages[0].granule_position, 0);
+        assert_eq!(pages[1].header_type, 0);
+        assert_eq!(pages[1].lacing.len(), MAX_SEGMENTS);
+        assert_eq!(pages[1].granule_position, NO_GRANULE);
+        assert_eq!(pages[2].header_type, CONTINUED);
+        assert_eq!(pages[2].lacing.len(), 47);
+        assert_eq!(pages[2].granule_position, 0);
+        let sequences: Vec<u32> = pages.iter().map(|page| page.sequence).collect();
+        assert_eq!(sequences, vec![0, 1, 2]);
+        assert!(join_packets(&pages) == packets);
+    }
+
+    #[test]
+    fn rewrite_comments_renumbers_the_pages_after_the_headers() {
+        let path = write_stream("rewrite", 2, 44100, 1000);
+        let long: String = (0..255 * 300).map(|_| 'x').collect();
+        let res = rewrite_comments(&path, |comments| {
+            comments.set("title", "rewritten");
+            comments.add("COMMENT", &long);
+        });
+        res.unwrap();
+        let pages = read_pages(&path);
+        let info = info(&path).unwrap();
+        fs::remove_file(&path).ok();
+        let sequences: Vec<u32> = pages.iter().map(|page| page.sequence).collect();
+        assert_eq!(sequences, (0..pages.len() as u32).collect::<Vec<_>>());
+        assert!(pages.len() > 3);
+        assert_eq!(pages[pages.len() - 1].granule_position, 1000);
+        assert_eq!(pages[pages.len() - 1].data, vec![0]);
+        assert_eq!(info.frames, 1000);
+        assert_eq!(info.comments.get("TITLE"), vec!["rewritten"]);
+        assert_eq!(info.comments.get("comment"), vec![&long[..]]);
+    }
+}
@@ -1,13 +1,19 @@
+use std::cell;
 use std::cmp;
+use std::collections;
 use std::error;
+use std::f32;
 use std::fmt;
 use std::fs;
 use std::io;
 use std::mem;
 use std::num;
 use std::path;
+use std::rc;
 use std::result;
 use std::str::FromStr;
+use ogg;
+use regex;
 use vorbis;
 
 pub type Result<T> = result::Result<T, Error>;
@@ -42,6 +48,8 @@ impl Stream for EmptyStream {
 }
 
 pub struct VorbisStream {
+    channels: usize,
+    rate: u64,
     offset: usize,
     packet: Vec<f32>,
     next_packet: Option<Vec<f32>>,
@@ -51,16 +59,22 @@ pub struct VorbisStream {
 impl VorbisStream {
     pub fn new(decoder: vorbis::Decoder<fs::File>) -> Result<VorbisStream> {
         let mut packets = decoder.into_packets();
+        let mut channels = 1;
+        let mut rate = 0;
         let first = if let Some(first) = packets.next() {
-            Some(try!(first)
-                     .data
-                     .iter()
-                     .map(|value| *value as f32 / i16::max_value() as f32)
-                     .collect())
+            let first = try!(first);
+            channels = cmp::max(first.channels as usize, 1);
+            rate = first.rate as u64;
+            Some(first.data
+                      .iter()
+                      .map(|value| *value as f32 / i16::max_value() as f32)
+                      .collect())
         } else {
             None
         };
         let mut stream = VorbisStream {
+            channels: channels,
+            rate: rate,
             offset: 0,
             packet: vec![],
             next_packet: first,
@@ -117,9 +131,71 @@ impl Stream for VorbisStream {
     }
 }
 
+/// The smallest of the numeric comments named `name` of a stream, if any.
+fn smallest_comment(decoder: &vorbis::Decoder<fs::File>, name: &str) -> Result<Option<u64>> {
+    let values = try!(decoder.get_comment(name));
+    values.iter()
+                .fold(Ok(None), |acc, value| {
+                    let res: Result<_> = acc.and_then(|acc| {
+                        let value = try!(u64::from_str(value));
+                        Ok(acc.map(|acc| cmp::min(acc, value))
+                              .or(Some(value)))
+                    });
+                    res
+                })
+}
+
+/// Reads the splice point of a track file the way `Track::vorbis` does.
+pub fn splice_point(path: &path::Path) -> Result<Option<u64>> {
+    let decoder = try!(vorbis::Decoder::new(try!(fs::File::open(path))));
+    smallest_comment(&decoder, "SPLICEPOINT")
+}
+
+/// Reads the minimum and maximum dwell counts a self-loop track file sets
+/// for its node through its MINDWELL and MAXDWELL comments.
+pub fn dwell(path: &path::Path) -> Result<(Option<u64>, Option<u64>)> {
+    let decoder = try!(vorbis::Decoder::new(try!(fs::File::open(path))));
+    Ok((try!(smallest_comment(&decoder, "MINDWELL")), try!(smallest_comment(&decoder, "MAXDWELL"))))
+}
+
+/// Reads the comments of a track file the way `Track::vorbis` does.
+///
+/// The vendor and the names of the comments come from the comment header,
+/// and the values of each name, in the order the names first appear, from
+/// the decoder.
+pub fn comments(path: &path::Path) -> Result<ogg::Comments> {
+    let header = {
+        let mut reader = io::BufReader::new(try!(fs::File::open(path)));
+        let (packets, _) = try!(ogg::read_headers(&mut reader));
+        try!(ogg::Comments::parse(&packets[1]))
+    };
+    let decoder = try!(vorbis::Decoder::new(try!(fs::File::open(path))));
+    let mut keys: Vec<String> = vec![];
+    for &(ref key, _) in header.comments.iter() {
+        if !keys.iter().any(|seen| seen.eq_ignore_ascii_case(key)) {
+            keys.push(key.clone());
+        }
+    }
+    let mut comments = vec![];
+    for key in keys {
+        for value in try!(decoder.get_comment(&key)) {
+            comments.push((key.clone(), value));
+        }
+    }
+    Ok(ogg::Comments {
+        vendor: header.vendor,
+        comments: comments,
+    })
+}
+
 pub struct Track {
     stream: Box<Stream>,
+    /// Samples left until the next track starts while the rest of this one
+    /// plays on.
     splice_point: Option<u64>,
+    /// Samples over which the track crossfades into the next one.
+    crossfade: Option<u64>,
+    channels: usize,
 }
 
 impl Track {
@@ -127,36 +203,127 @@ impl Track {
         Track {
             stream: Box::new(EmptyStream),
             splice_point: None,
+            crossfade: None,
+            channels: 1,
         }
     }
 
     pub fn vorbis(path: &path::Path) -> Result<Track> {
-        let display = path.display();
-        let file = match fs::File::open(&path) {
-            Err(why) => {
-                panic!("Couldn't open {}: {}",
-                       display,
-                       error::Error::description(&why))
-            }
-            Ok(file) => file,
-        };
+        Track::vorbis_with_crossfade(path, None, None)
+    }
 
+    /// Opens a track that crossfades into the next one over the number of
+    /// samples given by its CROSSFADE comments, or else over `seconds`.
+    ///
+    /// The crossfade starts at the splice point. Without one, it starts
+    /// early enough to end with the track, given its length in `frames`,
+    /// and without either there is no crossfade.
+    pub fn vorbis_with_crossfade(path: &path::Path,
+                                 seconds: Option<f64>,
+                                 frames: Option<u64>)
+                                 -> Result<Track> {
+        let file = try!(fs::File::open(&path));
         let decoder = try!(vorbis::Decoder::new(file));
-        let splice_point = try!(decoder.get_comment("SPLICEPOINT"));
-        let splice_point = splice_point.iter()
-                                       .fold(Ok(None), |acc, value| {
-                                           let res: Result<_> = acc.and_then(|acc| {
-                                               let value = try!(u64::from_str(value));
-                                               Ok(acc.map(|acc| cmp::min(acc, value))
-                                                     .or(Some(value)))
-                                           });
-                                           res
-                                       });
-        let splice_point = try!(splice_point);
+        let mut splice_point = try!(smallest_comment(&decoder, "SPLICEPOINT"));
+        let comment = try!(smallest_comment(&decoder, "CROSSFADE"));
         let stream = try!(VorbisStream::new(decoder));
+        let channels = stream.channels as u64;
+        let length = comment.unwrap_or_else(|| {
+            let frames = (seconds.unwrap_or(0.0) * stream.rate as f64).round() as u64;
+            frames * channels
+        });
+        let mut crossfade = if length > 0 { Some(length) } else { None };
+        if let (Some(length), None) = (crossfade, splice_point) {
+            splice_point = frames.map(|frames| {
+                let samples = frames * channels;
+                samples - cmp::min(length, samples)
+            });
+            if splice_point.is_none() {
+                crossfade = None;
+            }
+        }
         Ok(Track {
+            channels: stream.channels,
             stream: Box::new(stream),
             splice_point: splice_point,
+            crossfade: crossfade,
+        })
+    }
+
+    /// Plays several tracks in unison, e.g. the stems of a layer.
+    ///
+    /// The earliest splice point and shortest crossfade among the tracks
+    /// apply to all of them.
+    pub fn stack(tracks: Vec<Track>) -> Track {
+        let splice_point = tracks.iter().filter_map(|track| track.splice_point).min();
+        let crossfade = tracks.iter().filter_map(|track| track.crossfade).min();
+        let channels = tracks.iter().map(|track| track.channels).max().unwrap_or(1);
+        let streams = tracks.into_iter().map(|track| track.stream).collect();
+        Track {
+            stream: Box::new(Mixer::new(streams)),
+            splice_point: splice_point,
+            crossfade: crossfade,
+            channels: channels,
+        }
+    }
+
+    /// Fades the track in over `length` samples.
+    fn fade_in(&mut self, length: usize) {
+        let stream = mem::replace(&mut self.stream, Box::new(EmptyStream));
+        let frames = length / self.channels;
+        self.stream = Box::new(Crossfade::fade_in(stream, self.channels, frames));
+    }
+
+    /// Decodes up to `before` samples ahead of where the next track starts,
+    /// i.e. the splice point or else the end, and up to `after` samples of
+    /// the tail playing on past it.
+    pub fn window(mut self, before: usize, after: usize) -> Result<Window> {
+        let mut samples = collections::VecDeque::new();
+        let mut scratch = vec![];
+        loop {
+            if self.max_read() == 0 {
+                if self.is_eos() || self.splice_point == Some(0) {
+                    break;
+                }
+                try!(self.stream.load());
+                if self.max_read() == 0 && !self.is_eos() {
+                    break;
+                }
+                continue;
+            }
+            scratch.truncate(0);
+            scratch.resize(self.max_read(), 0.0);
+            self.read_add(&mut scratch);
+            samples.extend(scratch.iter().cloned());
+            let excess = samples.len().saturating_sub(before);
+            samples.drain(..excess);
+        }
+        let splice = samples.len();
+        let mut samples: Vec<f32> = samples.into_iter().collect();
+        let tails = if self.splice_point == Some(0) {
+            try!(self.load())
+        } else {
+            vec![]
+        };
+        let mut tail = Mixer::new(tails);
+        while samples.len() < splice + after {
+            let len = cmp::min(tail.max_read(), splice + after - samples.len());
+            if len == 0 {
+                try!(tail.load());
+                if tail.max_read() == 0 {
+                    break;
+                }
+                continue;
+            }
+            let start = samples.len();
+            samples.resize(start + len, 0.0);
+            tail.read_add(&mut samples[start..]);
+        }
+        Ok(Window {
+            samples: rc::Rc::new(samples),
+            splice: splice,
+            crossfade: self.crossfade,
+            channels: self.channels,
         })
     }
 
@@ -169,6 +336,10 @@ impl Track {
             }
         })
     }
+
+    fn crossfade_as_usize(&self) -> usize {
+        self.crossfade.map_or(0, |length| cmp::min(length, usize::max_value() as u64) as usize)
+    }
 }
 
 impl Stream for Track {
@@ -189,6 +360,7 @@ impl Stream for Track {
             panic!("out of bounds in Track");
         }
         self.stream.read_add(buf);
+        self.splice_point = self.splice_point.map(|sp| sp - buf.len() as u64);
     }
 
     fn load(&mut self) -> Result<Vec<Box<Stream>>> {
@@ -206,10 +378,196 @@ impl Stream for Track {
     }
 }
 
+/// Samples held in memory, shared by the streams playing them.
+struct Samples {
+    samples: rc::Rc<Vec<f32>>,
+    offset: usize,
+}
+
+impl Stream for Samples {
+    fn is_eos(&self) -> bool {
+        self.max_read() == 0
+    }
+
+    fn max_read(&self) -> usize {
+        self.samples.len() - self.offset
+    }
+
+    fn read_add(&mut self, buf: &mut [f32]) {
+        if buf.len() > self.max_read() {
+            panic!("out of bounds in Samples");
+        }
+        let data = &self.samples[self.offset..self.offset + buf.len()];
+        for (out, value) in buf.iter_mut().zip(data) {
+            *out += *value;
+        }
+        self.offset += buf.len();
+    }
+
+    fn load(&mut self) -> Result<Vec<Box<Stream>>> {
+        Ok(vec![])
+    }
+}
+
+/// The part of a track around where the next track starts, decoded once to
+/// be played any number of times.
+pub struct Window {
+    samples: rc::Rc<Vec<f32>>,
+    splice: usize,
+    crossfade: Option<u64>,
+    channels: usize,
+}
+
+impl Window {
+    /// Number of samples of the window ahead of where the next track starts.
+    pub fn before(&self) -> usize {
+        self.splice
+    }
+
+    /// Plays the window as a track that gives way to the next where the
+    /// original would.
+    pub fn track(&self) -> Track {
+        Track {
+            stream: Box::new(Samples {
+                samples: self.samples.clone(),
+                offset: 0,
+            }),
+            splice_point: Some(self.splice as u64),
+            crossfade: self.crossfade,
+            channels: self.channels,
+        }
+    }
+}
+
+/// Counts the tracks started by one or more players, for streams to start
+/// along with the next of them.
+#[derive(Clone, Default)]
+pub struct Cue(rc::Rc<cell::Cell<u64>>);
+
+impl Cue {
+    /// Holds `stream` back until the next track of the player starts.
+    pub fn wait(&self, stream: Box<Stream>) -> Cued {
+        Cued {
+            stream: stream,
+            cue: rc::Rc::downgrade(&self.0),
+            start: self.0.get(),
+            started: false,
+        }
+    }
+
+    fn advance(&self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+/// A stream held back until a player starts its next track.
+///
+/// It is silent until then, and ends unplayed if the player goes away first.
+pub struct Cued {
+    stream: Box<Stream>,
+    cue: rc::Weak<cell::Cell<u64>>,
+    start: u64,
+    started: bool,
+}
+
+impl Cued {
+    /// Whether the stream plays, or else waits if the player is still there.
+    fn state(&self) -> Option<bool> {
+        if self.started {
+            Some(true)
+        } else {
+            self.cue.upgrade().map(|count| count.get() != self.start)
+        }
+    }
+}
+
+impl Stream for Cued {
+    fn is_eos(&self) -> bool {
+        match self.state() {
+            Some(true) => self.stream.is_eos(),
+            Some(false) => false,
+            None => true,
+        }
+    }
+
+    fn max_read(&self) -> usize {
+        match self.state() {
+            Some(true) => self.stream.max_read(),
+            Some(false) => usize::max_value(),
+            None => 0,
+        }
+    }
+
+    fn read_add(&mut self, buf: &mut [f32]) {
+        if buf.len() > self.max_read() {
+            panic!("out of bounds in Cued");
+        }
+        if self.state() == Some(true) {
+            self.started = true;
+            self.stream.read_add(buf);
+        }
+    }
+
+    fn load(&mut self) -> Result<Vec<Box<Stream>>> {
+        if self.state() == Some(true) {
+            self.started = true;
+            self.stream.load()
+        } else {
+            Ok(vec![])
+        }
+    }
+}
+
+/// A change of course for a player.
+enum Change {
+    /// On to the next track of the play list.
+    Skip,
+    /// On to the first track of a new play list.
+    Swap(Box<Iterator<Item = Result<Track>>>),
+}
+
+/// Runtime controls of a player, taking effect at its next load.
+#[derive(Clone, Default)]
+pub struct Control(rc::Rc<cell::RefCell<Option<(Change, usize)>>>);
+
+impl Control {
+    /// Cuts the current track short and starts the next one of the play
+    /// list, fading the current one out over `fade` frames.
+    ///
+    /// A swap that is still pending is kept, with the new fade.
+    pub fn skip(&self, fade: usize) {
+        let mut pending = self.0.borrow_mut();
+        let change = match pending.take() {
+            Some((Change::Swap(tracks), _)) => Change::Swap(tracks),
+            _ => Change::Skip,
+        };
+        *pending = Some((change, fade));
+    }
+
+    /// Cuts the current track short and goes on with `tracks` in place of
+    /// the play list. The current track fades out over `fade` frames, or
+    /// stops at once without one.
+    pub fn swap(&self, tracks: Box<Iterator<Item = Result<Track>>>, fade: Option<usize>) {
+        *self.0.borrow_mut() = Some((Change::Swap(tracks), fade.unwrap_or(0)));
+    }
+
+    fn is_pending(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+}
+
+/// Number of tracks in a row a player skips over when they fail to open
+/// before it gives up.
+const MAX_SKIPS: usize = 16;
+
 pub struct Player {
     track: Track,
     lookahead: Option<Track>,
     play_list: Box<Iterator<Item = Result<Track>>>,
+    cue: Cue,
+    clock: rc::Rc<cell::Cell<u64>>,
+    control: Control,
+    skipped: rc::Rc<cell::RefCell<Vec<Error>>>,
 }
 
 impl Player {
@@ -218,6 +576,10 @@ impl Player {
             track: Track::empty(),
             lookahead: Some(Track::empty()),
             play_list: tracks,
+            cue: Cue::default(),
+            clock: rc::Rc::new(cell::Cell::new(0)),
+            control: Control::default(),
+            skipped: rc::Rc::new(cell::RefCell::new(vec![])),
         };
         if player.max_read() == 0 {
             let tails = try!(player.load());
@@ -225,6 +587,31 @@ impl Player {
         }
         Ok(player)
     }
+
+    pub fn cue(&self) -> Cue {
+        self.cue.clone()
+    }
+
+    /// Counts the tracks the player starts from here on on `cue`, e.g. one
+    /// shared with other players.
+    pub fn set_cue(&mut self, cue: Cue) {
+        self.cue = cue;
+    }
+
+    /// The number of samples read from the player so far.
+    pub fn clock(&self) -> rc::Rc<cell::Cell<u64>> {
+        self.clock.clone()
+    }
+
+    pub fn control(&self) -> Control {
+        self.control.clone()
+    }
+
+    /// The errors of the tracks that failed to open and were skipped, for
+    /// the owner of the player to report and clear.
+    pub fn skipped(&self) -> rc::Rc<cell::RefCell<Vec<Error>>> {
+        self.skipped.clone()
+    }
 }
 
 impl Stream for Player {
@@ -233,7 +620,11 @@ impl Stream for Player {
     }
 
     fn max_read(&self) -> usize {
-        self.track.max_read()
+        if self.control.is_pending() {
+            0
+        } else {
+            self.track.max_read()
+        }
     }
 
     fn read_add(&mut self, buf: &mut [f32]) {
@@ -241,36 +632,92 @@ impl Stream for Player {
             panic!("out of bounds in Player");
         }
         self.track.read_add(buf);
+        self.clock.set(self.clock.get() + buf.len() as u64);
     }
 
     fn load(&mut self) -> Result<Vec<Box<Stream>>> {
         let mut tails = vec![];
+        let change = self.control.0.borrow_mut().take();
+        if let Some((change, fade)) = change {
+            let track = mem::replace(&mut self.track, Track::empty());
+            if fade > 0 {
+                let channels = track.channels;
+                tails.push(Box::new(FadeOut::new(Box::new(track), channels, fade)) as Box<Stream>);
+            }
+            if let Change::Swap(tracks) = change {
+                self.play_list = tracks;
+            }
+        }
+        let mut skips = vec![];
+        let mut fade_in = 0;
         while self.track.max_read() == 0 {
-            let new_tails = self.track.load().map_err(|err| {
+            let crossfade = self.track.crossfade_as_usize();
+            let channels = self.track.channels;
+            let new_tails = try!(self.track.load().map_err(|err| {
                 self.track = Track::empty();
                 self.lookahead = None;
                 err
-            });
-            tails.extend(try!(new_tails));
+            }));
+            if crossfade > 0 && !new_tails.is_empty() {
+                fade_in = crossfade;
+                tails.extend(new_tails.into_iter().map(|tail| {
+                    Box::new(Crossfade::fade_out(tail, channels, crossfade / channels))
+                        as Box<Stream>
+                }));
+            } else {
+                tails.extend(new_tails);
+            }
             if self.track.is_eos() {
-                if let Some(new_track) = self.play_list.next() {
-                    self.track = try!(new_track);
-                } else {
-                    break;
+                match self.play_list.next() {
+                    Some(Ok(track)) => {
+                        self.track = track;
+                        if fade_in > 0 {
+                            self.track.fade_in(fade_in);
+                            fade_in = 0;
+                        }
+                        self.cue.advance();
+                    }
+                    Some(Err(err)) => {
+                        self.track = Track::empty();
+                        skips.push(err);
+                        if skips.len() >= MAX_SKIPS {
+                            self.lookahead = None;
+                            return Err(From::from(skips));
+                        }
+                    }
+                    None => {
+                        self.lookahead = None;
+                        break;
+                    }
                 }
             }
         }
+        self.skipped.borrow_mut().extend(skips);
         Ok(tails)
     }
 }
 
 pub struct Mixer {
     streams: Vec<Box<Stream>>,
+    position: u64,
 }
 
 impl Mixer {
     pub fn new(streams: Vec<Box<Stream>>) -> Mixer {
-        Mixer { streams: streams }
+        Mixer {
+            streams: streams,
+            position: 0,
+        }
+    }
+
+    /// Adds a stream to be mixed in from the current position on.
+    pub fn push(&mut self, stream: Box<Stream>) {
+        self.streams.push(stream);
+    }
+
+    /// Number of samples read from the mixer so far.
+    pub fn position(&self) -> u64 {
+        self.position
     }
 }
 
@@ -301,6 +748,7 @@ impl Stream for Mixer {
         for stream in self.streams.iter_mut() {
             stream.read_add(buf);
         }
+        self.position += buf.len() as u64;
     }
 
     fn load(&mut self) -> Result<Vec<Box<Stream>>> {
@@ -339,11 +787,312 @@ impl Stream for Mixer {
     }
 }
 
+/// Plays a stream after a number of samples of silence.
+pub struct Delayed {
+    stream: Box<Stream>,
+    delay: usize,
+}
+
+impl Delayed {
+    pub fn new(stream: Box<Stream>, delay: usize) -> Delayed {
+        Delayed {
+            stream: stream,
+            delay: delay,
+        }
+    }
+}
+
+impl Stream for Delayed {
+    fn is_eos(&self) -> bool {
+        self.delay == 0 && self.stream.is_eos()
+    }
+
+    fn max_read(&self) -> usize {
+        if self.delay > 0 {
+            self.delay
+        } else {
+            self.stream.max_read()
+        }
+    }
+
+    fn read_add(&mut self, buf: &mut [f32]) {
+        if buf.len() > self.max_read() {
+            panic!("out of bounds in Delayed");
+        }
+        if self.delay > 0 {
+            self.delay -= buf.len();
+        } else {
+            self.stream.read_add(buf);
+        }
+    }
+
+    fn load(&mut self) -> Result<Vec<Box<Stream>>> {
+        if self.delay > 0 {
+            Ok(vec![])
+        } else {
+            self.stream.load()
+        }
+    }
+}
+
+/// Scales a stream by a level that glides towards a shared target.
+pub struct Fader {
+    stream: Box<Stream>,
+    target: rc::Rc<cell::Cell<f32>>,
+    level: f32,
+    channels: usize,
+    step: f32,
+    scratch: Vec<f32>,
+}
+
+impl Fader {
+    /// The level takes `frames` frames of `channels` samples to glide all
+    /// the way from 0 to 1, and starts at the current target.
+    pub fn new(stream: Box<Stream>,
+               target: rc::Rc<cell::Cell<f32>>,
+               channels: usize,
+               frames: usize)
+               -> Fader {
+        let level = target.get();
+        Fader {
+            stream: stream,
+            target: target,
+            level: level,
+            channels: cmp::max(channels, 1),
+            step: 1.0 / cmp::max(frames, 1) as f32,
+            scratch: vec![],
+        }
+    }
+}
+
+impl Stream for Fader {
+    fn is_eos(&self) -> bool {
+        self.stream.is_eos()
+    }
+
+    fn max_read(&self) -> usize {
+        self.stream.max_read()
+    }
+
+    fn read_add(&mut self, buf: &mut [f32]) {
+        if buf.len() > self.max_read() {
+            panic!("out of bounds in Fader");
+        }
+
+        let target = self.target.get();
+        if self.level == target && target == 1.0 {
+            self.stream.read_add(buf);
+            return;
+        }
+
+        self.scratch.truncate(0);
+        self.scratch.resize(buf.len(), 0.0);
+        self.stream.read_add(&mut self.scratch);
+
+        for (out, frame) in buf.chunks_mut(self.channels).zip(self.scratch.chunks(self.channels)) {
+            if self.level < target {
+                self.level = (self.level + self.step).min(target);
+            } else if self.level > target {
+                self.level = (self.level - self.step).max(target);
+            }
+            for (out, value) in out.iter_mut().zip(frame) {
+                *out += *value * self.level;
+            }
+        }
+    }
+
+    fn load(&mut self) -> Result<Vec<Box<Stream>>> {
+        let tails = try!(self.stream.load());
+        let channels = self.channels;
+        let frames = (1.0 / self.step) as usize;
+        Ok(tails.into_iter()
+                .map(|tail| {
+                    let mut fader = Fader::new(tail, self.target.clone(), channels, frames);
+                    fader.level = self.level;
+                    Box::new(fader) as Box<Stream>
+                })
+                .collect())
+    }
+}
+
+/// Fades a stream out frame by frame and ends it once silent.
+pub struct FadeOut {
+    stream: Box<Stream>,
+    channels: usize,
+    length: usize,
+    remaining: usize,
+    scratch: Vec<f32>,
+}
+
+impl FadeOut {
+    /// The fade takes `length` frames of `channels` samples.
+    pub fn new(stream: Box<Stream>, channels: usize, length: usize) -> FadeOut {
+        FadeOut {
+            stream: stream,
+            channels: cmp::max(channels, 1),
+            length: length,
+            remaining: length,
+            scratch: vec![],
+        }
+    }
+}
+
+impl Stream for FadeOut {
+    fn is_eos(&self) -> bool {
+        self.remaining == 0 || self.stream.is_eos()
+    }
+
+    fn max_read(&self) -> usize {
+        cmp::min(self.remaining * self.channels, self.stream.max_read())
+    }
+
+    fn read_add(&mut self, buf: &mut [f32]) {
+        if buf.len() > self.max_read() {
+            panic!("out of bounds in FadeOut");
+        }
+
+        self.scratch.truncate(0);
+        self.scratch.resize(buf.len(), 0.0);
+        self.stream.read_add(&mut self.scratch);
+
+        for (out, frame) in buf.chunks_mut(self.channels).zip(self.scratch.chunks(self.channels)) {
+            let gain = self.remaining as f32 / self.length as f32;
+            for (out, value) in out.iter_mut().zip(frame) {
+                *out += *value * gain;
+            }
+            self.remaining -= 1;
+        }
+    }
+
+    fn load(&mut self) -> Result<Vec<Box<Stream>>> {
+        if self.remaining == 0 {
+            return Ok(vec![]);
+        }
+        let tails = try!(self.stream.load());
+        Ok(tails.into_iter()
+                .map(|tail| {
+                    let mut fade_out = FadeOut::new(tail, self.channels, self.length);
+                    fade_out.remaining = self.remaining;
+                    Box::new(fade_out) as Box<Stream>
+                })
+                .collect())
+    }
+}
+
+/// Fades a stream in or out with equal power over a number of frames, so
+/// that a stream faded in over another faded out keeps its loudness. A
+/// stream faded out ends once silent.
+pub struct Crossfade {
+    stream: Box<Stream>,
+    fade_in: bool,
+    channels: usize,
+    length: usize,
+    position: usize,
+    scratch: Vec<f32>,
+}
+
+impl Crossfade {
+    /// The fade takes `length` frames of `channels` samples.
+    pub fn fade_in(stream: Box<Stream>, channels: usize, length: usize) -> Crossfade {
+        Crossfade::new(stream, true, channels, length)
+    }
+
+    /// The fade takes `length` frames of `channels` samples.
+    pub fn fade_out(stream: Box<Stream>, channels: usize, length: usize) -> Crossfade {
+        Crossfade::new(stream, false, channels, length)
+    }
+
+    fn new(stream: Box<Stream>, fade_in: bool, channels: usize, length: usize) -> Crossfade {
+        Crossfade {
+            stream: stream,
+            fade_in: fade_in,
+            channels: cmp::max(channels, 1),
+            length: length,
+            position: 0,
+            scratch: vec![],
+        }
+    }
+}
+
+/// Gain of an equal power fade `position` frames into its `length`.
+fn equal_power_gain(fade_in: bool, position: usize, length: usize) -> f32 {
+    let angle = f32::consts::FRAC_PI_2 * cmp::min(position, length) as f32 / length as f32;
+    if fade_in {
+        angle.sin()
+    } else {
+        angle.cos()
+    }
+}
+
+impl Stream for Crossfade {
+    fn is_eos(&self) -> bool {
+        (!self.fade_in && self.position >= self.length) || self.stream.is_eos()
+    }
+
+    fn max_read(&self) -> usize {
+        if self.fade_in {
+            self.stream.max_read()
+        } else {
+            cmp::min((self.length - cmp::min(self.position, self.length)) * self.channels,
+                     self.stream.max_read())
+        }
+    }
+
+    fn read_add(&mut self, buf: &mut [f32]) {
+        if buf.len() > self.max_read() {
+            panic!("out of bounds in Crossfade");
+        }
+
+        if self.position >= self.length {
+            self.stream.read_add(buf);
+            return;
+        }
+
+        self.scratch.truncate(0);
+        self.scratch.resize(buf.len(), 0.0);
+        self.stream.read_add(&mut self.scratch);
+
+        for (out, frame) in buf.chunks_mut(self.channels).zip(self.scratch.chunks(self.channels)) {
+            let gain = equal_power_gain(self.fade_in, self.position, self.length);
+            for (out, value) in out.iter_mut().zip(frame) {
+                *out += *value * gain;
+            }
+            self.position += 1;
+        }
+    }
+
+    fn load(&mut self) -> Result<Vec<Box<Stream>>> {
+        if self.is_eos() {
+            return Ok(vec![]);
+        }
+        let tails = try!(self.stream.load());
+        let (fade_in, channels, length) = (self.fade_in, self.channels, self.length);
+        Ok(tails.into_iter()
+                .map(|tail| {
+                    let mut crossfade = Crossfade::new(tail, fade_in, channels, length);
+                    crossfade.position = self.position;
+                    Box::new(crossfade) as Box<Stream>
+                })
+                .collect())
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Parse(num::ParseIntError),
     Vorbis(vorbis::VorbisError),
+    Pattern(regex::Error),
+    Capture(String),
+    Command(String),
+    Syntax(usize, String),
+    Ogg(String),
+    Mismatch(String),
+    NoLayer(String),
+    NoStinger(String),
+    NoNode(String),
+    NoTempo(String),
+    Outdated(path::PathBuf),
     Multiple(Vec<Error>),
     AudioFormat,
     File(path::PathBuf, Box<Error>),
@@ -357,6 +1106,17 @@ impl error::Error for Error {
             &Error::Io(_) => "an I/O error",
             &Error::Parse(_) => "a parse error",
             &Error::Vorbis(_) => "a Vorbis decoder error",
+            &Error::Pattern(_) => "an invalid regular expression",
+            &Error::Capture(_) => "a missing named capture",
+            &Error::Command(_) => "an unknown command",
+            &Error::Syntax(_, _) => "a syntax error",
+            &Error::Ogg(_) => "a malformed Ogg Vorbis stream",
+            &Error::Mismatch(_) => "mismatching stems",
+            &Error::NoLayer(_) => "no such layer",
+            &Error::NoStinger(_) => "no such stinger",
+            &Error::NoNode(_) => "no such node",
+            &Error::NoTempo(_) => "no tempo for layer",
+            &Error::Outdated(_) => "an outdated compiled digraph",
             &Error::Multiple(_) => "multiple errors",
             &Error::AudioFormat => "inconsistent audio formats",
             &Error::File(_, _) => "an error occurred in a file",
@@ -371,6 +1131,7 @@ impl error::Error for Error {
             &Error::Io(ref err) => Some(err as &error::Error),
             &Error::Parse(ref err) => Some(err as &error::Error),
             &Error::Vorbis(ref err) => Some(err as &error::Error),
+            &Error::Pattern(ref err) => Some(err as &error::Error),
             &Error::File(_, ref err) => Some(err.deref() as &error::Error),
             &Error::Dir(_, ref err) => Some(err.deref() as &error::Error),
             _ => None,
@@ -390,6 +1151,12 @@ impl From<vorbis::VorbisError> for Error {
     }
 }
 
+impl From<regex::Error> for Error {
+    fn from(err: regex::Error) -> Error {
+        Error::Pattern(err)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::Io(err)
@@ -413,6 +1180,29 @@ impl fmt::Display for Error {
             &::stream::Error::Io(_) => write!(f, "{}", self.description()),
             &::stream::Error::Parse(_) => write!(f, "{}", self.description()),
             &::stream::Error::Vorbis(_) => write!(f, "{}", self.description()),
+            &::stream::Error::Pattern(_) => write!(f, "{}", self.description()),
+            &::stream::Error::Capture(ref name) => {
+                write!(f, "{} named '{}'", self.description(), name)
+            }
+            &::stream::Error::Command(ref line) => {
+                write!(f, "{} '{}'", self.description(), line)
+            }
+            &::stream::Error::Syntax(line, ref message) => {
+                write!(f, "{} on line {}: {}", self.description(), line, message)
+            }
+            &::stream::Error::Ogg(ref message) => write!(f, "{}: {}", self.description(), message),
+            &::stream::Error::Mismatch(ref message) => {
+                write!(f, "{}: {}", self.description(), message)
+            }
+            &::stream::Error::NoLayer(ref name) => write!(f, "{} '{}'", self.description(), name),
+            &::stream::Error::NoStinger(ref name) => {
+                write!(f, "{} '{}'", self.description(), name)
+            }
+            &::stream::Error::NoNode(ref name) => write!(f, "{} '{}'", self.description(), name),
+            &::stream::Error::NoTempo(ref name) => write!(f, "{} '{}'", self.description(), name),
+            &::stream::Error::Outdated(ref path) => {
+                write!(f, "{} '{}'", self.description(), path.display())
+            }
             &::stream::Error::Multiple(ref err) => {
                 let parts: Vec<_> = err.iter().map(::stream::Error::to_string).collect();
                 write!(f, "{}:\n * {}", self.description(), parts.join("\n * "))
@@ -426,3 +1216,156 @@ impl fmt::Display for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stream of `len` samples of `value`.
+    struct Constant {
+        value: f32,
+        len: usize,
+    }
+
+    impl Stream for Constant {
+        fn is_eos(&self) -> bool {
+            self.len == 0
+        }
+
+        fn max_read(&self) -> usize {
+            self.len
+        }
+
+        fn read_add(&mut self, buf: &mut [f32]) {
+            for sample in buf.iter_mut() {
+                *sample += self.value;
+            }
+            self.len -= buf.len();
+        }
+
+        fn load(&mut self) -> Result<Vec<Box<Stream>>> {
+            Ok(vec![])
+        }
+    }
+
+    fn track(value: f32, len: usize) -> Track {
+        Track {
+            stream: Box::new(Constant {
+                value: value,
+                len: len,
+            }),
+            splice_point: None,
+            crossfade: None,
+            channels: 1,
+        }
+    }
+
+    /// Reads `mixer` to its end the way the playback loop does.
+    fn drain(mixer: &mut Mixer) -> Vec<f32> {
+        let mut out = vec![];
+        while !mixer.is_eos() {
+            let max_read = cmp::min(mixer.max_read(), 1000);
+            if max_read == 0 {
+                mixer.load().unwrap();
+                continue;
+            }
+            let start = out.len();
+            out.resize(start + max_read, 0.0);
+            mixer.read_add(&mut out[start..]);
+        }
+        out
+    }
+
+    #[test]
+    fn cued_stream_starts_with_the_next_track() {
+        let tracks = vec![Ok(track(1.0, 4)), Ok(track(2.0, 4))];
+        let player = Player::new(Box::new(tracks.into_iter())).unwrap();
+        let cued = player.cue().wait(Box::new(Constant {
+            value: 10.0,
+            len: 2,
+        }));
+        let mut mixer = Mixer::new(vec![Box::new(player), Box::new(cued)]);
+        assert_eq!(drain(&mut mixer), vec![1.0, 1.0, 1.0, 1.0, 12.0, 12.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn cued_stream_ends_with_its_player() {
+        let player = Player::new(Box::new(vec![Ok(track(1.0, 2))].into_iter())).unwrap();
+        let cued = player.cue().wait(Box::new(Constant {
+            value: 10.0,
+            len: 2,
+        }));
+        let mut mixer = Mixer::new(vec![Box::new(player), Box::new(cued)]);
+        assert_eq!(drain(&mut mixer), vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn player_skips_tracks_that_fail() {
+        let tracks = vec![Ok(track(1.0, 2)), Err(Error::NoItems), Ok(track(2.0, 2))];
+        let player = Player::new(Box::new(tracks.into_iter())).unwrap();
+        let skipped = player.skipped();
+        let mut mixer = Mixer::new(vec![Box::new(player)]);
+        assert_eq!(drain(&mut mixer), vec![1.0, 1.0, 2.0, 2.0]);
+        assert_eq!(skipped.borrow().len(), 1);
+    }
+
+    #[test]
+    fn player_swaps_its_play_list_mid_track() {
+        let tracks = vec![Ok(track(1.0, 4)), Ok(track(1.0, 4))];
+        let player = Player::new(Box::new(tracks.into_iter())).unwrap();
+        let control = player.control();
+        let mut mixer = Mixer::new(vec![Box::new(player)]);
+        let mut buf = vec![0.0; 2];
+        mixer.read_add(&mut buf);
+        control.swap(Box::new(vec![Ok(track(2.0, 2))].into_iter()), None);
+        let mut samples = buf;
+        samples.extend(drain(&mut mixer));
+        assert_eq!(samples, vec![1.0, 1.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn next_track_starts_at_the_splice_point() {
+        let mut first = track(1.0, 4);
+        first.splice_point = Some(2);
+        let tracks = vec![Ok(first), Ok(track(2.0, 4))];
+        let player = Player::new(Box::new(tracks.into_iter())).unwrap();
+        let mut mixer = Mixer::new(vec![Box::new(player)]);
+        assert_eq!(drain(&mut mixer), vec![1.0, 1.0, 3.0, 3.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn fade_out_steps_per_frame() {
+        let stream = Box::new(Constant {
+            value: 1.0,
+            len: 8,
+        });
+        let mut mixer = Mixer::new(vec![Box::new(FadeOut::new(stream, 2, 2))]);
+        assert_eq!(drain(&mut mixer), vec![1.0, 1.0, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn window_replays_the_seam() {
+        let mut first = track(1.0, 6);
+        first.splice_point = Some(4);
+        let window = first.window(2, 1).unwrap();
+        assert_eq!(window.before(), 2);
+        let tracks = vec![Ok(window.track()), Ok(track(2.0, 2))];
+        let player = Player::new(Box::new(tracks.into_iter())).unwrap();
+        let mut mixer = Mixer::new(vec![Box::new(player)]);
+        assert_eq!(drain(&mut mixer), vec![1.0, 1.0, 3.0, 2.0]);
+    }
+
+    #[test]
+    fn equal_power_gain_keeps_the_power_steady() {
+        assert_eq!(equal_power_gain(true, 0, 4), 0.0);
+        assert_eq!(equal_power_gain(false, 0, 4), 1.0);
+        assert_eq!(equal_power_gain(true, 4, 4), 1.0);
+        assert_eq!(equal_power_gain(true, 8, 4), 1.0);
+        assert!(equal_power_gain(false, 4, 4).abs() < 1e-6);
+        for position in 0..5 {
+            let fade_in = equal_power_gain(true, position, 4);
+            let fade_out = equal_power_gain(false, position, 4);
+            assert!((fade_in * fade_in + fade_out * fade_out - 1.0).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Parsing shared by the line oriented files: manifests, compiled digraphs
+//! and histories.
+
+use digraph;
+use std::str::FromStr;
+use stream;
+
+pub fn error(line: usize, message: String) -> stream::Error {
+    stream::Error::Syntax(line, message)
+}
+
+pub fn parse_value<T: FromStr>(line: usize, key: &str, value: &str) -> stream::Result<T> {
+    T::from_str(value).map_err(|_| error(line, format!("invalid value '{}' for '{}'", value, key)))
+}
+
+pub fn parse_probability(line: usize, key: &str, value: &str) -> stream::Result<f64> {
+    let probability: f64 = try!(parse_value(line, key, value));
+    if probability >= 0.0 && probability <= 1.0 {
+        Ok(probability)
+    } else {
+        Err(error(line, format!("'{}' must be between 0 and 1", key)))
+    }
+}
+
+pub fn parse_non_negative(line: usize, key: &str, value: &str) -> stream::Result<f64> {
+    let number: f64 = try!(parse_value(line, key, value));
+    if number >= 0.0 {
+        Ok(number)
+    } else {
+        Err(error(line, format!("'{}' must not be negative", key)))
+    }
+}
+
+/// Splits an option of the form `key=value`.
+pub fn option<'a>(line: usize, word: &'a str) -> stream::Result<(&'a str, &'a str)> {
+    let mut parts = word.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key, value)),
+        _ => Err(error(line, format!("expected key=value, found '{}'", word))),
+    }
+}
+
+/// Checks that the minimum dwell count of `node` does not exceed its
+/// maximum.
+pub fn check_dwell(line: usize,
+                   builder: &digraph::DigraphBuilder,
+                   node: &str)
+                   -> stream::Result<()> {
+    match builder.dwell(node) {
+        (Some(min), Some(max)) if min > max => {
+            Err(error(line, "'min' must not exceed 'max'".to_string()))
+        }
+        _ => Ok(()),
+    }
+}
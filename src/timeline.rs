@@ -0,0 +1,435 @@
+use digraph;
+use std::cell;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::rc;
+use stream;
+
+/// Frame rate of the timecodes in edit decision lists.
+const EDL_FRAME_RATE: f64 = 25.0;
+
+/// Events per edit decision list, as event numbers have three digits.
+const EDL_EVENTS: usize = 999;
+
+/// A file format for timelines.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Audacity label track, with a label per track and per splice point.
+    Audacity,
+    /// CMX 3600 style edit decision list with an event per track.
+    Edl,
+    /// Comma separated values with a row per track.
+    Csv,
+}
+
+impl Format {
+    /// Picks the format by the extension of `path`: "txt", "edl" or "csv".
+    pub fn from_path(path: &path::Path) -> Option<Format> {
+        match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()) {
+            Some(ref ext) if ext == "txt" => Some(Format::Audacity),
+            Some(ref ext) if ext == "edl" => Some(Format::Edl),
+            Some(ref ext) if ext == "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// A track as it was played by a layer.
+#[derive(Clone, Debug)]
+struct Entry {
+    layer: String,
+    tail: String,
+    head: String,
+    label: Option<String>,
+    paths: Vec<path::PathBuf>,
+    /// Seconds into the session at which the track started.
+    start: f64,
+    /// Seconds the files of the track play for, if known.
+    duration: Option<f64>,
+    /// Seconds into the session at which the next track of the layer
+    /// started, if it has.
+    next: Option<f64>,
+}
+
+impl Entry {
+    fn arrow(&self) -> String {
+        match self.label {
+            Some(ref label) => format!("{} -> {} [{}]", self.tail, self.head, label),
+            None => format!("{} -> {}", self.tail, self.head),
+        }
+    }
+
+    /// Seconds into the session at which the files of the track have played
+    /// to their end, or else at which the next track started.
+    fn end(&self) -> f64 {
+        match self.duration {
+            Some(duration) => self.start + duration,
+            None => self.next.unwrap_or(self.start),
+        }
+    }
+
+    fn file_names(&self) -> Vec<String> {
+        self.paths
+            .iter()
+            .map(|path| {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| path.display().to_string())
+            })
+            .collect()
+    }
+}
+
+fn timecode(seconds: f64) -> String {
+    let frames = (seconds * EDL_FRAME_RATE).round() as u64;
+    let rate = EDL_FRAME_RATE as u64;
+    let seconds = frames / rate;
+    format!("{:02}:{:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            frames % rate)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", value.replace("\"", "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// A file the timeline is appended to.
+///
+/// An edit decision list goes on in a new file, named like the first with
+/// the part number appended, after `EDL_EVENTS` events.
+struct File {
+    path: path::PathBuf,
+    format: Format,
+    writer: io::BufWriter<fs::File>,
+    part: usize,
+    events: usize,
+}
+
+impl File {
+    fn create(path: &path::Path, format: Format) -> io::Result<File> {
+        let mut file = File {
+            path: path.to_path_buf(),
+            format: format,
+            writer: io::BufWriter::new(try!(fs::File::create(path))),
+            part: 1,
+            events: 0,
+        };
+        try!(file.write_header());
+        Ok(file)
+    }
+
+    /// The path of the part being written.
+    fn part_path(&self) -> path::PathBuf {
+        if self.part == 1 {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().map_or("".into(), |stem| stem.to_string_lossy());
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, self.part, ext.to_string_lossy()),
+            None => format!("{}-{}", stem, self.part),
+        };
+        self.path.with_file_name(name)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        match self.format {
+            Format::Audacity => Ok(()),
+            Format::Edl => {
+                try!(writeln!(self.writer, "TITLE: digraph123"));
+                writeln!(self.writer, "FCM: NON-DROP FRAME")
+            }
+            Format::Csv => writeln!(self.writer, "layer,start,end,splice,tail,head,variant,file"),
+        }
+    }
+
+    /// Starts the next part of an edit decision list.
+    fn roll(&mut self) -> io::Result<()> {
+        try!(self.writer.flush());
+        self.part += 1;
+        self.events = 0;
+        self.writer = io::BufWriter::new(try!(fs::File::create(self.part_path())));
+        self.write_header()
+    }
+
+    fn write(&mut self, entry: &Entry, end: f64, splice: Option<f64>) -> io::Result<()> {
+        match self.format {
+            Format::Audacity => {
+                try!(writeln!(self.writer,
+                              "{:.6}\t{:.6}\t{}: {}",
+                              entry.start,
+                              end,
+                              entry.layer,
+                              entry.arrow()));
+                if let Some(splice) = splice {
+                    try!(writeln!(self.writer,
+                                  "{:.6}\t{:.6}\tsplice {}: {}",
+                                  splice,
+                                  splice,
+                                  entry.layer,
+                                  entry.arrow()));
+                }
+            }
+            Format::Edl => {
+                if self.events == EDL_EVENTS {
+                    try!(self.roll());
+                }
+                self.events += 1;
+                try!(writeln!(self.writer, ""));
+                try!(writeln!(self.writer,
+                              "{:03}  AX       AA    C        {} {} {} {}",
+                              self.events,
+                              timecode(0.0),
+                              timecode(end - entry.start),
+                              timecode(entry.start),
+                              timecode(end)));
+                try!(writeln!(self.writer,
+                              "* FROM CLIP NAME: {}",
+                              entry.file_names().join(", ")));
+                try!(writeln!(self.writer, "* COMMENT: {}: {}", entry.layer, entry.arrow()));
+                if let Some(splice) = splice {
+                    try!(writeln!(self.writer, "* SPLICE: {}", timecode(splice)));
+                }
+            }
+            Format::Csv => {
+                try!(writeln!(self.writer,
+                              "{},{:.6},{:.6},{},{},{},{},{}",
+                              csv_field(&entry.layer),
+                              entry.start,
+                              end,
+                              splice.map(|splice| format!("{:.6}", splice)).unwrap_or_default(),
+                              csv_field(&entry.tail),
+                              csv_field(&entry.head),
+                              csv_field(entry.label.as_ref().map_or("", String::as_str)),
+                              csv_field(&entry.file_names().join(";"))));
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self, err: io::Error) -> stream::Error {
+        stream::Error::File(self.part_path(), Box::new(stream::Error::Io(err)))
+    }
+}
+
+#[derive(Default)]
+struct TimelineState {
+    /// The entries not written yet, in order of their start.
+    entries: Vec<Entry>,
+    files: Vec<File>,
+}
+
+impl TimelineState {
+
+    /// Appends the entries up to the first one whose layer has not gone on
+    /// to its next track, or every entry with `all`, to the files.
+    fn save(&mut self, all: bool) -> stream::Result<()> {
+        let count = if all {
+            self.entries.len()
+        } else {
+            let len = self.entries.len();
+            self.entries.iter().position(|entry| entry.next.is_none()).unwrap_or(len)
+        };
+        let entries: Vec<Entry> = self.entries.drain(..count).collect();
+        let rows: Vec<(Entry, f64, Option<f64>)> = entries.into_iter()
+            .map(|entry| {
+                let end = entry.end();
+                // The next track starting before the end marks a splice.
+                let splice = entry.next.into_iter().filter(|&next| next + 1e-6 < end).next();
+                (entry, end, splice)
+            })
+            .collect();
+        let mut result = Ok(());
+        for file in self.files.iter_mut() {
+            let res = rows.iter()
+                          .fold(Ok(()), |res, &(ref entry, end, splice)| {
+                              res.and_then(|_| file.write(entry, end, splice))
+                          })
+                          .and_then(|_| file.writer.flush());
+            if let Err(err) = res {
+                if result.is_ok() {
+                    result = Err(file.error(err));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// The tracks played by the layers of a session, recorded as they start
+/// and appended to files once their layers have gone on.
+#[derive(Clone, Default)]
+pub struct Timeline(rc::Rc<cell::RefCell<TimelineState>>);
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline::default()
+    }
+
+    /// Creates `path` to write the timeline to in `format`.
+    pub fn add_file(&self, path: &path::Path, format: Format) -> stream::Result<()> {
+        let file = try!(File::create(path, format).map_err(|err| {
+            stream::Error::File(path.to_path_buf(), Box::new(stream::Error::Io(err)))
+        }));
+        self.0.borrow_mut().files.push(file);
+        Ok(())
+    }
+
+    /// Records that `layer` started playing the files of `step` at `start`
+    /// seconds into the session, at `rate` frames per second.
+    pub fn record(&self, layer: &str, start: f64, step: &digraph::Step, rate: u32) {
+        let mut state = self.0.borrow_mut();
+        if let Some(latest) = state.entries.iter_mut().rev().find(|entry| entry.layer == layer) {
+            latest.next = Some(start);
+        }
+        let index = state.entries
+                         .iter()
+                         .rposition(|entry| entry.start <= start)
+                         .map_or(0, |index| index + 1);
+        state.entries.insert(index,
+                             Entry {
+                                 layer: layer.to_string(),
+                                 tail: step.tail.clone(),
+                                 head: step.head.clone(),
+                                 label: step.label.clone(),
+                                 paths: step.paths.clone(),
+                                 start: start,
+                                 duration: step.frames.and_then(|frames| {
+                                     if rate > 0 {
+                                         Some(frames as f64 / rate as f64)
+                                     } else {
+                                         None
+                                     }
+                                 }),
+                                 next: None,
+                             });
+    }
+
+    /// Appends the tracks whose layers have gone on to the files, in order
+    /// of their start.
+    pub fn save(&self) -> stream::Result<()> {
+        self.0.borrow_mut().save(false)
+    }
+
+    /// Appends every track left to the files, at the end of the session.
+    pub fn finish(&self) -> stream::Result<()> {
+        self.0.borrow_mut().save(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+
+    fn step(tail: &str, head: &str, label: Option<&str>) -> digraph::Step {
+        digraph::Step {
+            tail: tail.to_string(),
+            head: head.to_string(),
+            label: label.map(|label| label.to_string()),
+            paths: vec![path::PathBuf::from(format!("/music/{}-{}.ogg", tail, head))],
+            frames: None,
+            crossfade: None,
+        }
+    }
+
+    fn temp_path(name: &str) -> path::PathBuf {
+        env::temp_dir().join(format!("digraph123-timeline-{}", name))
+    }
+
+    fn read_file(path: &path::Path) -> String {
+        let mut text = String::new();
+        fs::File::open(path).unwrap().read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn timecode_counts_frames() {
+        assert_eq!(timecode(0.0), "00:00:00:00");
+        assert_eq!(timecode(3725.5), "01:02:05:13");
+    }
+
+    #[test]
+    fn csv_field_quotes_separators() {
+        assert_eq!(csv_field("woods"), "woods");
+        assert_eq!(csv_field("calm,dark"), "\"calm,dark\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn save_appends_tracks_in_order_once_their_layers_go_on() {
+        let path = temp_path("order.csv");
+        let timeline = Timeline::new();
+        timeline.add_file(&path, Format::Csv).unwrap();
+        timeline.record("melody", 0.0, &step("start", "woods", None), 44100);
+        timeline.record("drums", 1.0, &step("start", "march", Some("calm,dark")), 44100);
+        timeline.record("melody", 4.0, &step("woods", "village", None), 44100);
+        timeline.save().unwrap();
+        let saved = read_file(&path);
+        timeline.record("drums", 5.0, &step("march", "march", None), 44100);
+        timeline.finish().unwrap();
+        let finished = read_file(&path);
+        fs::remove_file(&path).ok();
+        assert_eq!(saved,
+                   "layer,start,end,splice,tail,head,variant,file\n\
+                    melody,0.000000,4.000000,,start,woods,,start-woods.ogg\n");
+        assert_eq!(finished,
+                   "layer,start,end,splice,tail,head,variant,file\n\
+                    melody,0.000000,4.000000,,start,woods,,start-woods.ogg\n\
+                    drums,1.000000,5.000000,,start,march,\"calm,dark\",start-march.ogg\n\
+                    melody,4.000000,4.000000,,woods,village,,woods-village.ogg\n\
+                    drums,5.000000,5.000000,,march,march,,march-march.ogg\n");
+    }
+
+    #[test]
+    fn edl_goes_on_in_a_new_file_after_999_events() {
+        let path = temp_path("roll.edl");
+        let second = temp_path("roll-2.edl");
+        let timeline = Timeline::new();
+        timeline.add_file(&path, Format::Edl).unwrap();
+        for i in 0..(EDL_EVENTS + 1) {
+            timeline.record("melody", i as f64, &step("woods", "woods", None), 44100);
+        }
+        timeline.finish().unwrap();
+        let first_part = read_file(&path);
+        let second_part = read_file(&second);
+        fs::remove_file(&path).ok();
+        fs::remove_file(&second).ok();
+        assert_eq!(first_part.matches("* FROM CLIP NAME").count(), EDL_EVENTS);
+        assert!(first_part.contains("\n999  AX"));
+        assert_eq!(second_part,
+                   "TITLE: digraph123\n\
+                    FCM: NON-DROP FRAME\n\
+                    \n\
+                    001  AX       AA    C        00:00:00:00 00:00:00:00 00:16:39:00 \
+                    00:16:39:00\n\
+                    * FROM CLIP NAME: woods-woods.ogg\n\
+                    * COMMENT: melody: woods -> woods\n");
+    }
+
+    #[test]
+    fn end_comes_from_the_length_of_the_step() {
+        let path = temp_path("end.txt");
+        let timeline = Timeline::new();
+        timeline.add_file(&path, Format::Audacity).unwrap();
+        let mut first = step("start", "woods", None);
+        first.frames = Some(88200);
+        timeline.record("melody", 0.5, &first, 44100);
+        timeline.record("melody", 1.5, &step("woods", "woods", Some("1")), 44100);
+        timeline.finish().unwrap();
+        let text = read_file(&path);
+        fs::remove_file(&path).ok();
+        assert_eq!(text,
+                   "0.500000\t2.500000\tmelody: start -> woods\n\
+                    1.500000\t1.500000\tsplice melody: start -> woods\n\
+                    1.500000\t1.500000\tmelody: woods -> woods [1]\n");
+    }
+}
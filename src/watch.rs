@@ -0,0 +1,134 @@
+use builder;
+use builder::VoiceConfig;
+use digraph::Digraph;
+use grammar::Grammar;
+use history;
+use std::fs;
+use std::io;
+use std::path;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+use stream;
+
+type Snapshot = Vec<(path::PathBuf, Option<time::SystemTime>, u64)>;
+
+fn snapshot(dir: &path::Path) -> io::Result<Snapshot> {
+    let mut snapshot = vec![];
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let metadata = try!(entry.metadata());
+        snapshot.push((entry.path(), metadata.modified().ok(), metadata.len()));
+    }
+    snapshot.sort();
+    Ok(snapshot)
+}
+
+/// Polls a directory for added, removed and modified files.
+pub struct DirWatcher {
+    dir: path::PathBuf,
+    snapshot: Snapshot,
+    dirty: bool,
+}
+
+impl DirWatcher {
+    pub fn new<P: AsRef<path::Path>>(dir: P) -> io::Result<DirWatcher> {
+        let dir = dir.as_ref().to_path_buf();
+        let snapshot = try!(snapshot(&dir));
+        Ok(DirWatcher {
+            dir: dir,
+            snapshot: snapshot,
+            dirty: false,
+        })
+    }
+
+    /// Returns true once the directory has changed and then stayed the same
+    /// for one polling period, so that files still being written are not
+    /// picked up halfway.
+    pub fn poll(&mut self) -> io::Result<bool> {
+        let snapshot = try!(snapshot(&self.dir));
+        if snapshot != self.snapshot {
+            self.snapshot = snapshot;
+            self.dirty = true;
+            Ok(false)
+        } else if self.dirty {
+            self.dirty = false;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+/// A layer rescanned after its directories changed.
+pub struct Reload {
+    /// The index of the layer among those given to `spawn`.
+    pub layer: usize,
+    pub result: stream::Result<(VoiceConfig, Digraph)>,
+    /// Files skipped while scanning.
+    pub warnings: Vec<stream::Error>,
+    /// The files played by a history, identified by their contents.
+    pub identities: Option<history::Identities>,
+}
+
+/// Polls the directories of each layer once a second on a separate thread,
+/// and rescans a layer there whenever its directories have changed.
+///
+/// Each layer is given by its stem directories and their watchers. With
+/// `identify`, the files recorded in a history are identified there as well.
+pub fn spawn(layers: Vec<(Vec<String>, Vec<DirWatcher>)>,
+             grammar: Grammar,
+             identify: bool)
+             -> mpsc::Receiver<Reload> {
+    let (sender, receiver) = mpsc::channel();
+    if layers.is_empty() {
+        return receiver;
+    }
+    thread::spawn(move || {
+        let mut layers = layers;
+        loop {
+            thread::sleep(time::Duration::from_secs(1));
+            for (i, &mut (ref dirs, ref mut watchers)) in layers.iter_mut().enumerate() {
+                let mut changed = Ok(false);
+                for watcher in watchers.iter_mut() {
+                    match watcher.poll() {
+                        Ok(true) => changed = changed.map(|_| true),
+                        Ok(false) => {}
+                        Err(err) => changed = Err(err),
+                    }
+                }
+                let reload = match changed {
+                    Ok(false) => continue,
+                    Ok(true) => {
+                        let mut warnings = vec![];
+                        let result = builder::stems(&grammar, dirs, &mut warnings);
+                        let identities = match result {
+                            Ok((_, ref digraph)) if identify => {
+                                Some(history::identify(digraph.files()))
+                            }
+                            _ => None,
+                        };
+                        Reload {
+                            layer: i,
+                            result: result,
+                            warnings: warnings,
+                            identities: identities,
+                        }
+                    }
+                    Err(err) => {
+                        Reload {
+                            layer: i,
+                            result: Err(stream::Error::Io(err)),
+                            warnings: vec![],
+                            identities: None,
+                        }
+                    }
+                };
+                if sender.send(reload).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}